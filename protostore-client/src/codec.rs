@@ -0,0 +1,595 @@
+//! The wire format, shared with the server so the two can't drift apart.
+
+use std::io;
+
+use bytes::{BigEndian, BufMut, ByteOrder, Bytes, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+
+/// Response lengths that don't fit in the u16 length field are sent as
+/// this marker followed by the real length as a u32.
+pub const EXTENDED_LEN: u16 = 0xFFFF;
+
+/// Responses that carry an error instead of a value are sent with this
+/// marker in the length field, followed by a single status byte.
+pub const STATUS_LEN: u16 = 0xFFFE;
+
+/// Capability a client can set with a `C` frame: compressed values are
+/// returned as stored instead of being decompressed by the server.
+pub const CAP_COMPRESSED: u32 = 1;
+
+/// Capability a client can set with a `C` frame: values are prefixed
+/// with the u64 version of their record.
+pub const CAP_VERSIONS: u32 = 2;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+    Ok,
+    Corrupt,
+    // A conditional write found a different version than expected
+    Conflict,
+    // Writes are turned away while the server is read-only
+    ReadOnly,
+    // The value would be too large for a record
+    TooLarge,
+}
+
+impl Status {
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Ok => 0,
+            Status::Corrupt => 1,
+            Status::Conflict => 2,
+            Status::ReadOnly => 3,
+            Status::TooLarge => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Status> {
+        match code {
+            0 => Some(Status::Ok),
+            1 => Some(Status::Corrupt),
+            2 => Some(Status::Conflict),
+            3 => Some(Status::ReadOnly),
+            4 => Some(Status::TooLarge),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub id: u32,
+    pub status: Status,
+    pub body: Bytes,
+}
+
+impl Response {
+    /// Appends the response as the server sends it: `u32 id, u16 len`
+    /// and the body, with the markers above for statuses and long bodies.
+    pub fn encode(&self, dst: &mut BytesMut) {
+        dst.reserve(4 + 2 + 4 + self.body.len());
+        dst.put_u32_be(self.id);
+        if self.status != Status::Ok {
+            dst.put_u16_be(STATUS_LEN);
+            dst.put_u8(self.status.code());
+            return;
+        }
+
+        if self.body.len() < STATUS_LEN as usize {
+            dst.put_u16_be(self.body.len() as u16);
+        } else {
+            dst.put_u16_be(EXTENDED_LEN);
+            dst.put_u32_be(self.body.len() as u32);
+        }
+        dst.put_slice(&self.body);
+    }
+
+    /// Takes the first complete response off `buf`.
+    pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Response>> {
+        if buf.len() < 4 + 2 {
+            return Ok(None);
+        }
+        let id = BigEndian::read_u32(&buf[..4]);
+        match BigEndian::read_u16(&buf[4..6]) {
+            STATUS_LEN => {
+                if buf.len() < 4 + 2 + 1 {
+                    return Ok(None);
+                }
+                let status = Status::from_code(buf[6]).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "unknown response status")
+                })?;
+                buf.advance(4 + 2 + 1);
+                Ok(Some(Response {
+                    id,
+                    status,
+                    body: Bytes::new(),
+                }))
+            }
+            EXTENDED_LEN => {
+                if buf.len() < 4 + 2 + 4 {
+                    return Ok(None);
+                }
+                let len = BigEndian::read_u32(&buf[6..10]) as usize;
+                Ok(Response::split_body(buf, id, 4 + 2 + 4, len))
+            }
+            len => Ok(Response::split_body(buf, id, 4 + 2, len as usize)),
+        }
+    }
+
+    fn split_body(buf: &mut BytesMut, id: u32, header: usize, len: usize) -> Option<Response> {
+        if buf.len() < header + len {
+            return None;
+        }
+        buf.advance(header);
+        Some(Response {
+            id,
+            status: Status::Ok,
+            body: buf.split_to(len).freeze(),
+        })
+    }
+}
+
+/// A request as clients send it. Every frame starts with its u32 length
+/// and a type byte.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Read {
+        id: u32,
+        uuid: [u8; 16],
+    },
+    ReadKey {
+        id: u32,
+        key: Bytes,
+    },
+    Write {
+        id: u32,
+        uuid: [u8; 16],
+        value: Bytes,
+    },
+    // Write that expires after `ttl` seconds, 0 expiring it right away
+    WriteTtl {
+        id: u32,
+        uuid: [u8; 16],
+        ttl: u32,
+        value: Bytes,
+    },
+    // Write that only goes through if the record is at `expected_version`
+    WriteVersion {
+        id: u32,
+        uuid: [u8; 16],
+        expected_version: u64,
+        value: Bytes,
+    },
+    Append {
+        id: u32,
+        uuid: [u8; 16],
+        value: Bytes,
+    },
+    Capabilities {
+        id: u32,
+        flags: u32,
+    },
+    Scan {
+        id: u32,
+        start: [u8; 16],
+        end: [u8; 16],
+        prefix_len: u8,
+        limit: u32,
+    },
+    Admin {
+        id: u32,
+        command: Bytes,
+    },
+    Ping {
+        id: u32,
+    },
+}
+
+impl Frame {
+    pub fn id(&self) -> u32 {
+        match *self {
+            Frame::Read { id, .. }
+            | Frame::ReadKey { id, .. }
+            | Frame::Write { id, .. }
+            | Frame::WriteTtl { id, .. }
+            | Frame::WriteVersion { id, .. }
+            | Frame::Append { id, .. }
+            | Frame::Capabilities { id, .. }
+            | Frame::Scan { id, .. }
+            | Frame::Admin { id, .. }
+            | Frame::Ping { id } => id,
+        }
+    }
+
+    pub fn set_id(&mut self, new: u32) {
+        match self {
+            Frame::Read { id, .. }
+            | Frame::ReadKey { id, .. }
+            | Frame::Write { id, .. }
+            | Frame::WriteTtl { id, .. }
+            | Frame::WriteVersion { id, .. }
+            | Frame::Append { id, .. }
+            | Frame::Capabilities { id, .. }
+            | Frame::Scan { id, .. }
+            | Frame::Admin { id, .. }
+            | Frame::Ping { id } => *id = new,
+        }
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Read { id, uuid } => {
+                header(dst, b'R', 16 + 4);
+                dst.put_slice(uuid);
+                dst.put_u32_be(*id);
+            }
+            Frame::ReadKey { id, key } => {
+                header(dst, b'K', 4 + 2 + key.len());
+                dst.put_u32_be(*id);
+                dst.put_u16_be(key.len() as u16);
+                dst.put_slice(key);
+            }
+            Frame::Write { id, uuid, value } => {
+                header(dst, b'W', 16 + 4 + value.len());
+                dst.put_slice(uuid);
+                dst.put_u32_be(*id);
+                dst.put_slice(value);
+            }
+            Frame::WriteTtl {
+                id,
+                uuid,
+                ttl,
+                value,
+            } => {
+                header(dst, b'T', 16 + 4 + 4 + value.len());
+                dst.put_slice(uuid);
+                dst.put_u32_be(*id);
+                dst.put_u32_be(*ttl);
+                dst.put_slice(value);
+            }
+            Frame::WriteVersion {
+                id,
+                uuid,
+                expected_version,
+                value,
+            } => {
+                header(dst, b'V', 16 + 4 + 8 + value.len());
+                dst.put_slice(uuid);
+                dst.put_u32_be(*id);
+                dst.put_u64_be(*expected_version);
+                dst.put_slice(value);
+            }
+            Frame::Append { id, uuid, value } => {
+                header(dst, b'A', 16 + 4 + value.len());
+                dst.put_slice(uuid);
+                dst.put_u32_be(*id);
+                dst.put_slice(value);
+            }
+            Frame::Capabilities { id, flags } => {
+                header(dst, b'C', 4 + 4);
+                dst.put_u32_be(*id);
+                dst.put_u32_be(*flags);
+            }
+            Frame::Scan {
+                id,
+                start,
+                end,
+                prefix_len,
+                limit,
+            } => {
+                header(dst, b'S', 4 + 16 + 16 + 1 + 4);
+                dst.put_u32_be(*id);
+                dst.put_slice(start);
+                dst.put_slice(end);
+                dst.put_u8(*prefix_len);
+                dst.put_u32_be(*limit);
+            }
+            Frame::Admin { id, command } => {
+                header(dst, b'M', 4 + command.len());
+                dst.put_u32_be(*id);
+                dst.put_slice(command);
+            }
+            Frame::Ping { id } => {
+                header(dst, b'P', 4);
+                dst.put_u32_be(*id);
+            }
+        }
+    }
+
+    /// Takes the first complete frame off `buf`. Frames whose length
+    /// doesn't match their type are rejected, reading on from the wrong
+    /// place would garble every frame after them.
+    pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u32(&buf[..4]) as usize;
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Err(invalid("empty frame"));
+        }
+
+        // Length of everything after the type byte
+        let payload = len - 1;
+        let kind = buf[4];
+        let fits = match kind {
+            b'R' => payload == 16 + 4,
+            b'K' => {
+                payload >= 4 + 2
+                    && payload == 4 + 2 + BigEndian::read_u16(&buf[4 + 1 + 4..4 + 1 + 6]) as usize
+            }
+            b'W' | b'A' => payload >= 16 + 4,
+            b'T' => payload >= 16 + 4 + 4,
+            b'V' => payload >= 16 + 4 + 8,
+            b'C' => payload == 4 + 4,
+            b'S' => payload == 4 + 16 + 16 + 1 + 4,
+            b'M' => payload >= 4,
+            b'P' => payload == 4,
+            _ => return Err(invalid(&format!("unknown frame type {}", kind))),
+        };
+        if !fits {
+            return Err(invalid(&format!(
+                "{} byte frame doesn't fit type {}",
+                len, kind as char
+            )));
+        }
+
+        buf.advance(4 + 1);
+        let mut frame = buf.split_to(payload);
+        let frame = match kind {
+            b'R' => Frame::Read {
+                uuid: uuid(&frame[..16]),
+                id: BigEndian::read_u32(&frame[16..20]),
+            },
+            b'K' => Frame::ReadKey {
+                id: BigEndian::read_u32(&frame[..4]),
+                key: frame.split_off(4 + 2).freeze(),
+            },
+            b'W' => Frame::Write {
+                uuid: uuid(&frame[..16]),
+                id: BigEndian::read_u32(&frame[16..20]),
+                value: frame.split_off(16 + 4).freeze(),
+            },
+            b'T' => Frame::WriteTtl {
+                uuid: uuid(&frame[..16]),
+                id: BigEndian::read_u32(&frame[16..20]),
+                ttl: BigEndian::read_u32(&frame[20..24]),
+                value: frame.split_off(16 + 4 + 4).freeze(),
+            },
+            b'V' => Frame::WriteVersion {
+                uuid: uuid(&frame[..16]),
+                id: BigEndian::read_u32(&frame[16..20]),
+                expected_version: BigEndian::read_u64(&frame[20..28]),
+                value: frame.split_off(16 + 4 + 8).freeze(),
+            },
+            b'A' => Frame::Append {
+                uuid: uuid(&frame[..16]),
+                id: BigEndian::read_u32(&frame[16..20]),
+                value: frame.split_off(16 + 4).freeze(),
+            },
+            b'C' => Frame::Capabilities {
+                id: BigEndian::read_u32(&frame[..4]),
+                flags: BigEndian::read_u32(&frame[4..8]),
+            },
+            b'S' => Frame::Scan {
+                id: BigEndian::read_u32(&frame[..4]),
+                start: uuid(&frame[4..20]),
+                end: uuid(&frame[20..36]),
+                prefix_len: frame[36],
+                limit: BigEndian::read_u32(&frame[37..41]),
+            },
+            b'M' => Frame::Admin {
+                id: BigEndian::read_u32(&frame[..4]),
+                command: frame.split_off(4).freeze(),
+            },
+            _ => Frame::Ping {
+                id: BigEndian::read_u32(&frame[..4]),
+            },
+        };
+        Ok(Some(frame))
+    }
+}
+
+fn uuid(bytes: &[u8]) -> [u8; 16] {
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(bytes);
+    uuid
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reserves room for the frame and writes its length and type. `len`
+// doesn't count the type byte.
+fn header(dst: &mut BytesMut, kind: u8, len: usize) {
+    dst.reserve(4 + 1 + len);
+    dst.put_u32_be((1 + len) as u32);
+    dst.put_u8(kind);
+}
+
+/// The client end of a connection: frames out, responses in.
+#[derive(Debug, Default)]
+pub struct ClientCodec;
+
+impl Encoder for ClientCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        item.encode(dst);
+        Ok(())
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Response>> {
+        Response::decode(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{Frame, Response, Status, EXTENDED_LEN, STATUS_LEN};
+
+    fn round_trip(response: Response) {
+        let mut buf = BytesMut::new();
+        response.encode(&mut buf);
+        // Partial responses are left in the buffer
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        assert_eq!(None, Response::decode(&mut partial).unwrap());
+
+        assert_eq!(Some(response), Response::decode(&mut buf).unwrap());
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn responses() {
+        round_trip(Response {
+            id: 1,
+            status: Status::Ok,
+            body: Bytes::from(&b"abc"[..]),
+        });
+        round_trip(Response {
+            id: 2,
+            status: Status::Ok,
+            body: Bytes::from(vec![7; 70_000]),
+        });
+        round_trip(Response {
+            id: 3,
+            status: Status::Conflict,
+            body: Bytes::new(),
+        });
+    }
+
+    #[test]
+    fn markers() {
+        let mut buf = BytesMut::new();
+        buf.put_u32_be(9);
+        buf.put_u16_be(STATUS_LEN);
+        buf.put_u8(42);
+        assert!(Response::decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_be(9);
+        buf.put_u16_be(EXTENDED_LEN);
+        buf.put_u32_be(2);
+        buf.put_slice(b"ab");
+        let response = Response::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&b"ab"[..], &response.body[..]);
+    }
+
+    #[test]
+    fn frames() {
+        let uuid = [1; 16];
+        let value = Bytes::from(&b"abc"[..]);
+        let frames = vec![
+            Frame::Read { id: 1, uuid },
+            Frame::ReadKey {
+                id: 2,
+                key: value.clone(),
+            },
+            Frame::Write {
+                id: 3,
+                uuid,
+                value: value.clone(),
+            },
+            Frame::WriteTtl {
+                id: 4,
+                uuid,
+                ttl: 60,
+                value: Bytes::new(),
+            },
+            Frame::WriteVersion {
+                id: 5,
+                uuid,
+                expected_version: 9,
+                value: value.clone(),
+            },
+            Frame::Append {
+                id: 6,
+                uuid,
+                value: value.clone(),
+            },
+            Frame::Capabilities { id: 7, flags: 3 },
+            Frame::Scan {
+                id: 8,
+                start: uuid,
+                end: [0xFF; 16],
+                prefix_len: 2,
+                limit: 10,
+            },
+            Frame::Admin {
+                id: 9,
+                command: value.clone(),
+            },
+            Frame::Ping { id: 10 },
+        ];
+
+        let mut buf = BytesMut::new();
+        for frame in frames.iter() {
+            frame.encode(&mut buf);
+        }
+        // Partial frames are left in the buffer
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        for frame in frames.iter().take(frames.len() - 1) {
+            assert_eq!(Some(frame), Frame::decode(&mut partial).unwrap().as_ref());
+        }
+        assert_eq!(None, Frame::decode(&mut partial).unwrap());
+
+        for frame in frames {
+            assert_eq!(Some(frame), Frame::decode(&mut buf).unwrap());
+        }
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn reject_frames() {
+        let key = b"user:1234";
+        for key_len in &[key.len() + 1, key.len() - 1] {
+            let mut buf = BytesMut::new();
+            buf.put_u32_be(1 + 4 + 2 + key.len() as u32);
+            buf.put_u8(b'K');
+            buf.put_u32_be(42);
+            buf.put_u16_be(*key_len as u16);
+            buf.put_slice(key);
+            assert!(Frame::decode(&mut buf).is_err());
+        }
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_be(1 + 16);
+        buf.put_u8(b'R');
+        buf.put_slice(&[0; 16]);
+        assert!(Frame::decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_be(1 + 4);
+        buf.put_u8(b'X');
+        buf.put_u32_be(42);
+        assert!(Frame::decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn frame_ids() {
+        let mut frame = Frame::Write {
+            id: 1,
+            uuid: [0; 16],
+            value: Bytes::from(&b"abc"[..]),
+        };
+        frame.set_id(42);
+        assert_eq!(42, frame.id());
+
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf);
+        assert_eq!(4 + 1 + 16 + 4 + 3, buf.len());
+        assert_eq!(b'W', buf[4]);
+        assert_eq!(&[0, 0, 0, 42], &buf[4 + 1 + 16..4 + 1 + 16 + 4]);
+    }
+}
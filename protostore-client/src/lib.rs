@@ -0,0 +1,534 @@
+//! Async client for protostore.
+//!
+//! Requests are multiplexed over a pool of connections: frames are tagged
+//! with a request id unique to their connection, and a task per connection
+//! hands responses back to whoever sent the frame with that id. Connections that fail
+//! are opened again by the next request that lands on them.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+use futures::{pin_mut, select, FutureExt, SinkExt, StreamExt};
+use log::{debug, warn};
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+
+pub mod codec;
+
+pub use codec::{ClientCodec, Frame, Response, Status};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub connections: usize,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    // How long a connection that failed to open is left alone
+    pub reconnect_delay: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            connections: 1,
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+            reconnect_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    // The server answered with something other than Ok
+    Status(Status),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::Status(status) => write!(f, "server answered {:?}", status),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub struct Client {
+    addr: SocketAddr,
+    config: Config,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
+}
+
+struct Slot {
+    connection: Option<Connection>,
+    // When the slot last tried to connect and failed
+    failed_at: Option<Instant>,
+}
+
+impl Client {
+    /// Opens every connection of the pool, failing if any can't be.
+    pub async fn connect(addr: SocketAddr, config: Config) -> Result<Client, Error> {
+        let count = config.connections.max(1);
+        let mut slots = Vec::with_capacity(count);
+        for _ in 0..count {
+            let connection = Connection::open(addr, &config).await?;
+            slots.push(Mutex::new(Slot {
+                connection: Some(connection),
+                failed_at: None,
+            }));
+        }
+        Ok(Client {
+            addr,
+            config,
+            slots,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The value of `uuid`, None if it isn't stored.
+    pub async fn get(&self, uuid: [u8; 16]) -> Result<Option<Bytes>, Error> {
+        let response = self.ok(Frame::Read { id: 0, uuid }).await?;
+        value(response.body)
+    }
+
+    /// The value of a byte-string key, None if it isn't stored.
+    pub async fn get_key(&self, key: &[u8]) -> Result<Option<Bytes>, Error> {
+        let key = Bytes::from(key);
+        let response = self.ok(Frame::ReadKey { id: 0, key }).await?;
+        value(response.body)
+    }
+
+    /// Values of `uuids`, in the same order. Requests are spread over
+    /// the pool and sent without waiting for each other.
+    pub async fn multi_get(&self, uuids: &[[u8; 16]]) -> Result<Vec<Option<Bytes>>, Error> {
+        future::try_join_all(uuids.iter().map(|uuid| self.get(*uuid))).await
+    }
+
+    pub async fn put(&self, uuid: [u8; 16], value: &[u8]) -> Result<(), Error> {
+        let value = Bytes::from(value);
+        self.ok(Frame::Write { id: 0, uuid, value }).await?;
+        Ok(())
+    }
+
+    /// Stores a value that expires after `ttl`, rounded down to seconds.
+    pub async fn put_ttl(&self, uuid: [u8; 16], value: &[u8], ttl: Duration) -> Result<(), Error> {
+        let frame = Frame::WriteTtl {
+            id: 0,
+            uuid,
+            ttl: ttl.as_secs() as u32,
+            value: Bytes::from(value),
+        };
+        self.ok(frame).await?;
+        Ok(())
+    }
+
+    /// Deletes `uuid` by writing an empty value that has already expired.
+    pub async fn delete(&self, uuid: [u8; 16]) -> Result<(), Error> {
+        let frame = Frame::WriteTtl {
+            id: 0,
+            uuid,
+            ttl: 0,
+            value: Bytes::new(),
+        };
+        self.ok(frame).await?;
+        Ok(())
+    }
+
+    /// Runs an admin command, see `Admin::run` in the server.
+    pub async fn admin(&self, command: &str) -> Result<String, Error> {
+        let command = Bytes::from(command.as_bytes());
+        let response = self.ok(Frame::Admin { id: 0, command }).await?;
+        Ok(String::from_utf8_lossy(&response.body).into_owned())
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.ok(Frame::Ping { id: 0 }).await?;
+        Ok(())
+    }
+
+    /// Sends any frame and returns the response whatever its status. The
+    /// id of the frame is replaced with one unique to its connection.
+    pub async fn request(&self, frame: Frame) -> Result<Response, Error> {
+        let connection = self.connection().await?;
+        connection.request(frame, self.config.request_timeout).await
+    }
+
+    async fn ok(&self, frame: Frame) -> Result<Response, Error> {
+        let response = self.request(frame).await?;
+        match response.status {
+            Status::Ok => Ok(response),
+            status => Err(Error::Status(status)),
+        }
+    }
+
+    // The next connection of the pool, opened again if it has failed.
+    async fn connection(&self) -> Result<Connection, Error> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        {
+            let slot = self.slots[index].lock().unwrap();
+            if let Some(connection) = slot.connection.as_ref().filter(|c| !c.is_closed()) {
+                return Ok(connection.clone());
+            }
+            if let Some(failed_at) = slot.failed_at {
+                if failed_at.elapsed() < self.config.reconnect_delay {
+                    return Err(not_connected().into());
+                }
+            }
+        }
+
+        debug!("reconnecting to {}", self.addr);
+        let opened = Connection::open(self.addr, &self.config).await;
+        let mut slot = self.slots[index].lock().unwrap();
+        match opened {
+            Ok(connection) => {
+                slot.connection = Some(connection.clone());
+                slot.failed_at = None;
+                Ok(connection)
+            }
+            Err(e) => {
+                warn!("could not reconnect to {}: {}", self.addr, e);
+                slot.connection = None;
+                slot.failed_at = Some(Instant::now());
+                Err(e)
+            }
+        }
+    }
+}
+
+// Reads are answered with the u64 version of the record before the
+// value, or nothing at all when there isn't one.
+fn value(body: Bytes) -> Result<Option<Bytes>, Error> {
+    match body.len() {
+        0 => Ok(None),
+        1..=7 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response too short for a version",
+        )
+        .into()),
+        _ => Ok(Some(body.slice_from(8))),
+    }
+}
+
+type Reply = oneshot::Sender<io::Result<Response>>;
+
+// Requests that were sent and not answered yet, by the id they went
+// out with
+#[derive(Default)]
+struct Pending {
+    next_id: u32,
+    replies: HashMap<u32, Reply>,
+}
+
+impl Pending {
+    fn insert(&mut self, reply: Reply) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.replies.insert(id, reply);
+        id
+    }
+}
+
+// Forgets a request once its caller stops waiting for it, whether it
+// was answered, timed out or was dropped
+struct Waiting<'a> {
+    pending: &'a Mutex<Pending>,
+    id: u32,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().replies.remove(&self.id);
+    }
+}
+
+#[derive(Clone)]
+struct Connection {
+    requests: mpsc::Sender<Frame>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Connection {
+    async fn open(addr: SocketAddr, config: &Config) -> Result<Connection, Error> {
+        let stream = match Timeout::new(TcpStream::connect(&addr), config.connect_timeout).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(Error::Timeout),
+        };
+        stream.set_nodelay(true)?;
+
+        let (requests, incoming) = mpsc::channel(1024);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let framed = Framed::new(stream, ClientCodec);
+        tokio::spawn(run(framed, incoming, pending.clone()));
+        let connection = Connection { requests, pending };
+
+        // Versions tell values that are stored empty from missing ones
+        let flags = codec::CAP_VERSIONS;
+        let frame = Frame::Capabilities { id: 0, flags };
+        let response = connection.request(frame, config.request_timeout).await?;
+        match response.status {
+            Status::Ok => Ok(connection),
+            status => Err(Error::Status(status)),
+        }
+    }
+
+    async fn request(&self, mut frame: Frame, timeout: Duration) -> Result<Response, Error> {
+        let (tx, rx) = oneshot::channel();
+        let id = self.pending.lock().unwrap().insert(tx);
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+        frame.set_id(id);
+
+        self.requests
+            .clone()
+            .send(frame)
+            .await
+            .map_err(|_| not_connected())?;
+        match Timeout::new(rx, timeout).await {
+            Ok(Ok(response)) => Ok(response?),
+            // The connection task went away without answering
+            Ok(Err(_)) => Err(not_connected().into()),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+}
+
+// Writes frames as they are requested and routes responses back by id,
+// until the connection fails or every handle to it is dropped. Writing
+// and reading go on side by side, so a server that stops reading until
+// its responses are taken can't wedge the connection.
+async fn run(
+    framed: Framed<TcpStream, ClientCodec>,
+    mut incoming: mpsc::Receiver<Frame>,
+    pending: Arc<Mutex<Pending>>,
+) {
+    let (mut sink, mut stream) = framed.split();
+
+    let error = {
+        let mut frames = (&mut incoming).map(Ok::<_, io::Error>);
+        // Frames that are already queued go out in one write, it's
+        // flushed whenever the queue runs dry
+        let send = sink.send_all(&mut frames).fuse();
+
+        let receive = async {
+            loop {
+                match stream.next().await {
+                    Some(Ok(response)) => {
+                        let reply = pending.lock().unwrap().replies.remove(&response.id);
+                        if let Some(tx) = reply {
+                            let _ = tx.send(Ok(response));
+                        }
+                    }
+                    Some(Err(e)) => break e,
+                    None => {
+                        break io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")
+                    }
+                }
+            }
+        }
+        .fuse();
+
+        pin_mut!(send, receive);
+        select! {
+            sent = send => match sent {
+                Ok(()) => return,
+                Err(e) => e,
+            },
+            e = receive => e,
+        }
+    };
+
+    debug!("connection failed: {}", error);
+    // Requests sent after this fail to be queued, so nothing is left
+    // waiting on the ones drained here
+    incoming.close();
+    for (_, tx) in pending.lock().unwrap().replies.drain() {
+        let _ = tx.send(Err(io::Error::new(error.kind(), error.to_string())));
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "not connected")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::{Client, Config, Error, Frame, Response, Status};
+
+    // Never answered
+    const SILENT: [u8; 16] = [0xFF; 16];
+    // Makes the server drop the connection
+    const HANG_UP: [u8; 16] = [0xEE; 16];
+
+    // A server that answers a read of `uuid` with version 1 and the value
+    // `[uuid[0]; uuid[0]]`, holding reads back until `batch` of them came
+    // in on a connection and answering those in reverse. Returns how many
+    // connections it accepted so far.
+    fn serve(batch: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let socket = socket.unwrap();
+                thread::spawn(move || answer(socket, batch));
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn answer(mut socket: std::net::TcpStream, batch: usize) {
+        let mut buf = BytesMut::new();
+        let mut reads = vec![];
+        loop {
+            let mut chunk = [0; 4096];
+            match socket.read(&mut chunk) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+
+            let mut out = BytesMut::new();
+            while let Some(frame) = Frame::decode(&mut buf).unwrap() {
+                match frame {
+                    Frame::Read { uuid, .. } if uuid == SILENT => (),
+                    Frame::Read { uuid, .. } if uuid == HANG_UP => return,
+                    Frame::Read { id, uuid } => reads.push((id, uuid)),
+                    frame => ok(frame.id(), Bytes::new()).encode(&mut out),
+                }
+            }
+            if reads.len() >= batch {
+                for (id, uuid) in reads.drain(..).rev() {
+                    let mut body = vec![];
+                    body.put_u64_be(1);
+                    body.extend_from_slice(&vec![uuid[0]; uuid[0] as usize]);
+                    ok(id, Bytes::from(body)).encode(&mut out);
+                }
+            }
+            if socket.write_all(&out).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn ok(id: u32, body: Bytes) -> Response {
+        Response {
+            id,
+            status: Status::Ok,
+            body,
+        }
+    }
+
+    fn config(connections: usize) -> Config {
+        Config {
+            connections,
+            request_timeout: Duration::from_millis(100),
+            reconnect_delay: Duration::from_millis(0),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn responses_out_of_order() {
+        let (addr, _) = serve(4);
+        let mut rt = Runtime::new().unwrap();
+        let values = rt
+            .block_on(async {
+                let client = Client::connect(addr, config(1)).await?;
+                client
+                    .multi_get(&[[1; 16], [2; 16], [3; 16], [4; 16]])
+                    .await
+            })
+            .unwrap();
+
+        let expected: Vec<_> = (1..=4u8)
+            .map(|n| Some(Bytes::from(vec![n; n as usize])))
+            .collect();
+        assert_eq!(expected, values);
+    }
+
+    #[test]
+    fn pool_spreads_requests() {
+        let (addr, accepted) = serve(1);
+        let mut rt = Runtime::new().unwrap();
+        let values = rt
+            .block_on(async {
+                let client = Client::connect(addr, config(3)).await?;
+                client.multi_get(&[[1; 16], [2; 16], [3; 16]]).await
+            })
+            .unwrap();
+
+        assert_eq!(3, accepted.load(Ordering::SeqCst));
+        assert_eq!(Some(Bytes::from(vec![3; 3])), values[2]);
+    }
+
+    #[test]
+    fn timeout_forgets_request() {
+        let (addr, _) = serve(1);
+        let mut rt = Runtime::new().unwrap();
+        let client = rt.block_on(Client::connect(addr, config(1))).unwrap();
+
+        match rt.block_on(client.get(SILENT)) {
+            Err(Error::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        let connection = client.slots[0].lock().unwrap().connection.clone().unwrap();
+        assert!(connection.pending.lock().unwrap().replies.is_empty());
+
+        // The connection is still usable
+        let value = rt.block_on(client.get([2; 16])).unwrap();
+        assert_eq!(Some(Bytes::from(vec![2; 2])), value);
+    }
+
+    #[test]
+    fn reconnect_after_hang_up() {
+        let (addr, accepted) = serve(1);
+        let mut rt = Runtime::new().unwrap();
+        let client = rt.block_on(Client::connect(addr, config(1))).unwrap();
+
+        match rt.block_on(client.get(HANG_UP)) {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected the connection to fail, got {:?}", other),
+        }
+        let value = rt.block_on(client.get([1; 16])).unwrap();
+        assert_eq!(Some(Bytes::from(vec![1])), value);
+        assert_eq!(2, accepted.load(Ordering::SeqCst));
+    }
+}
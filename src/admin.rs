@@ -0,0 +1,269 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::dataset::Datasets;
+use crate::latency;
+use crate::metrics::metrics;
+use crate::placement::{Layout, Slot};
+
+/// Server state operators can inspect and change with admin frames.
+#[derive(Debug)]
+pub struct Admin {
+    started: Instant,
+    data_dir: PathBuf,
+    layout: Layout,
+    read_only: AtomicBool,
+    reload_requested: AtomicBool,
+    // Commands that change the server have to carry this, they are
+    // turned down when there is none
+    token: Option<String>,
+}
+
+impl Admin {
+    pub fn new(data_dir: PathBuf, layout: Layout, token: Option<String>) -> Admin {
+        Admin {
+            started: Instant::now(),
+            data_dir,
+            layout,
+            read_only: AtomicBool::new(false),
+            reload_requested: AtomicBool::new(false),
+            token,
+        }
+    }
+
+    /// Whether writes and appends are being turned away.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// True once after a `reload-dataset` command. Reloads are left to
+    /// the thread that handles SIGHUP, loading a large table of contents
+    /// would hold up every connection of a TCP thread.
+    pub fn take_reload_request(&self) -> bool {
+        self.reload_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Runs an admin command and returns the body of its response.
+    /// `reload-dataset` and `toggle-read-only` take the admin token as
+    /// their argument.
+    pub fn run(&self, command: &str, datasets: &Datasets) -> String {
+        let (name, arg) = match command.find(' ') {
+            Some(i) => (&command[..i], command[i + 1..].trim()),
+            None => (command, ""),
+        };
+        let response = match name {
+            "" | "info" => self.info(datasets),
+            "stat" => stat(arg, datasets),
+            "latency" => latency::report(),
+            "reload-dataset" | "toggle-read-only" if !self.authorized(arg) => {
+                json!({ "error": format!("{} needs the admin token", name) })
+            }
+            "reload-dataset" => {
+                self.reload_requested.store(true, Ordering::SeqCst);
+                json!({
+                    "reload_requested": true,
+                    "generation": datasets.current().generation,
+                })
+            }
+            "toggle-read-only" => {
+                // fetch_xor returns the previous value
+                let read_only = !self.read_only.fetch_xor(true, Ordering::SeqCst);
+                json!({ "read_only": read_only })
+            }
+            _ => json!({ "error": format!("unknown command: {}", command) }),
+        };
+        response.to_string()
+    }
+
+    fn authorized(&self, token: &str) -> bool {
+        match self.token {
+            // Doesn't stop at the first difference, so timing doesn't
+            // give the token away
+            Some(ref expected) => {
+                expected.len() == token.len()
+                    && expected
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            }
+            None => false,
+        }
+    }
+
+    fn info(&self, datasets: &Datasets) -> Value {
+        let dataset = datasets.current();
+        let metrics = metrics();
+        let slots = |slots: &[Slot]| slots.iter().map(slot).collect::<Vec<Value>>();
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "read_only": self.read_only(),
+            "data_dir": self.data_dir,
+            "dataset": {
+                "path": dataset.path,
+                "generation": dataset.generation,
+                "toc_entries": dataset.toc.len(),
+                "overlay_entries": dataset.overlay.len(),
+                "max_len": dataset.toc.max_len(),
+            },
+            "layout": {
+                "main": slot(&self.layout.main),
+                "aio": slots(&self.layout.aio),
+                "tcp": slots(&self.layout.tcp),
+            },
+            "connections": {
+                "open": metrics.open_connections(),
+                "total": metrics.connections(),
+            },
+            "aio": {
+                "submits": metrics.aio_submits(),
+                "submitted_ops": metrics.aio_submitted_ops(),
+                "completions": metrics.aio_completions(),
+                "inflight": metrics.aio_inflight(),
+            },
+        })
+    }
+}
+
+// Where the current record of a uuid is stored.
+fn stat(uuid: &str, datasets: &Datasets) -> Value {
+    let uuid = match Uuid::parse_str(uuid) {
+        Ok(uuid) => *uuid.as_bytes(),
+        Err(e) => return json!({ "error": format!("bad uuid {:?}: {}", uuid, e) }),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time drift!")
+        .as_secs();
+
+    let dataset = datasets.current();
+    match dataset.lookup(&uuid) {
+        Some(record) => json!({
+            "found": true,
+            "generation": dataset.generation,
+            "offset": record.offset,
+            "len": record.len,
+            "flags": record.flags,
+            "checksum": record.checksum,
+            "version": record.version,
+            "expires_at": record.expires_at,
+            "expired": record.is_expired(now),
+            // Written since the dataset was built
+            "overlay": dataset.overlay.lookup(&uuid).is_some(),
+        }),
+        None => json!({ "found": false, "generation": dataset.generation }),
+    }
+}
+
+fn slot(slot: &Slot) -> Value {
+    json!({ "pu": slot.pu, "node": slot.node })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    use serde_json::Value;
+
+    use super::Admin;
+    use crate::backend::ReadBackend;
+    use crate::dataset::Datasets;
+    use crate::placement::{Layout, Slot};
+    use crate::toc::tests::write_toc;
+
+    fn admin_and_datasets(dir: &TempDir) -> (Admin, Datasets) {
+        write_toc(dir.path(), &[[1; 16]], &[0], &[4], 4);
+        fs::write(dir.path().join("protostore.data"), b"abcd").unwrap();
+
+        let layout = Layout {
+            main: Slot { pu: 0, node: 0 },
+            aio: vec![Slot { pu: 1, node: 0 }],
+            tcp: vec![Slot { pu: 2, node: 1 }],
+        };
+        let admin = Admin::new(PathBuf::from(dir.path()), layout, Some("secret".to_owned()));
+        let datasets = Datasets::open(dir.path(), ReadBackend::Buffered, false).unwrap();
+        (admin, datasets)
+    }
+
+    #[test]
+    fn info() {
+        let dir = TempDir::new("admin").unwrap();
+        let (admin, datasets) = admin_and_datasets(&dir);
+
+        let info: Value = serde_json::from_str(&admin.run("info", &datasets)).unwrap();
+        assert_eq!(1, info["dataset"]["generation"]);
+        assert_eq!(1, info["dataset"]["toc_entries"]);
+        assert_eq!(4, info["dataset"]["max_len"]);
+        assert_eq!(2, info["layout"]["tcp"][0]["pu"]);
+        assert_eq!(false, info["read_only"]);
+    }
+
+    #[test]
+    fn need_token() {
+        let dir = TempDir::new("admin").unwrap();
+        let (admin, datasets) = admin_and_datasets(&dir);
+
+        for command in &[
+            "toggle-read-only",
+            "toggle-read-only secreT",
+            "reload-dataset",
+        ] {
+            let error: Value = serde_json::from_str(&admin.run(command, &datasets)).unwrap();
+            assert!(error["error"].is_string());
+        }
+        assert!(!admin.read_only());
+        assert!(!admin.take_reload_request());
+
+        let layout = Layout {
+            main: Slot { pu: 0, node: 0 },
+            aio: vec![],
+            tcp: vec![],
+        };
+        let without_token = Admin::new(PathBuf::from(dir.path()), layout, None);
+        without_token.run("toggle-read-only ", &datasets);
+        assert!(!without_token.read_only());
+    }
+
+    #[test]
+    fn commands() {
+        let dir = TempDir::new("admin").unwrap();
+        let (admin, datasets) = admin_and_datasets(&dir);
+
+        assert_eq!(
+            r#"{"read_only":true}"#,
+            admin.run("toggle-read-only secret", &datasets)
+        );
+        assert!(admin.read_only());
+        assert_eq!(
+            r#"{"read_only":false}"#,
+            admin.run("toggle-read-only secret", &datasets)
+        );
+
+        assert!(!admin.take_reload_request());
+        admin.run("reload-dataset secret", &datasets);
+        assert!(admin.take_reload_request());
+        assert!(!admin.take_reload_request());
+
+        let stat = admin.run("stat 01010101-0101-0101-0101-010101010101", &datasets);
+        let stat: Value = serde_json::from_str(&stat).unwrap();
+        assert_eq!(true, stat["found"]);
+        assert_eq!(0, stat["offset"]);
+        assert_eq!(4, stat["len"]);
+        assert_eq!(1, stat["generation"]);
+        let stat: Value = serde_json::from_str(&admin.run("stat 0000", &datasets)).unwrap();
+        assert!(stat["error"].is_string());
+
+        let latency: Value = serde_json::from_str(&admin.run("latency", &datasets)).unwrap();
+        assert!(latency["io"]["count"].is_u64());
+
+        let error: Value = serde_json::from_str(&admin.run("shutdown", &datasets)).unwrap();
+        assert_eq!("unknown command: shutdown", error["error"]);
+    }
+}
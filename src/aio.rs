@@ -1,13 +1,17 @@
 use std::default::Default;
+use std::fmt;
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use mio;
 
 use core::pin::Pin;
 use futures::channel::{mpsc, oneshot};
 use futures::executor;
+use futures::sink::SinkExt;
 use futures::stream::Stream;
 use futures::task::Context;
 use futures::{Future, Poll};
@@ -24,19 +28,28 @@ use tokio_net::util::PollEvented;
 use libc;
 use slab::Slab;
 
-use log::{info, trace};
+use log::{error, info, trace};
+
+use crate::health::health;
+use crate::latency::{self, Stage};
+use crate::metrics::metrics;
+
+// Tells sessions apart in traces
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug)]
 pub enum Message {
     PRead(
-        DirectFile,
+        Arc<DirectFile>,
         usize,
         usize,
         BytesMut,
         oneshot::Sender<io::Result<(BytesMut, Option<io::Error>)>>,
+        // When the read was sent to the session
+        Instant,
     ),
     PWrite(
-        DirectFile,
+        Arc<DirectFile>,
         usize,
         BytesMut,
         oneshot::Sender<io::Result<(BytesMut, Option<io::Error>)>>,
@@ -46,13 +59,87 @@ pub enum Message {
 #[derive(Debug)]
 pub struct Session {
     pub inner: mpsc::Sender<Message>,
+    id: usize,
     thread: JoinHandle<()>,
     pthread: libc::pthread_t,
 }
 
 #[derive(Debug, Clone)]
-struct SessionHandle {
+pub struct SessionHandle {
     inner: mpsc::Sender<Message>,
+    id: usize,
+}
+
+impl SessionHandle {
+    /// Id of the session the requests go to.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Reads `len` bytes at `offset` into `buf`. Both `offset` and
+    /// `len` must be aligned for O_DIRECT.
+    pub async fn pread(
+        &self,
+        file: Arc<DirectFile>,
+        offset: usize,
+        len: usize,
+        buf: BytesMut,
+    ) -> io::Result<BytesMut> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.clone();
+        if inner
+            .send(Message::PRead(file, offset, len, buf, tx, Instant::now()))
+            .await
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "aio session is gone",
+            ));
+        }
+
+        match rx.await {
+            Ok(Ok((buf, None))) => Ok(buf),
+            Ok(Ok((_, Some(e)))) => Err(e),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "aio session dropped the request",
+            )),
+        }
+    }
+
+    /// Writes `buf` at `offset`. Both `offset` and the length of `buf`
+    /// must be aligned for O_DIRECT.
+    pub async fn pwrite(
+        &self,
+        file: Arc<DirectFile>,
+        offset: usize,
+        buf: BytesMut,
+    ) -> io::Result<BytesMut> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.clone();
+        if inner
+            .send(Message::PWrite(file, offset, buf, tx))
+            .await
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "aio session is gone",
+            ));
+        }
+
+        match rx.await {
+            Ok(Ok((buf, None))) => Ok(buf),
+            Ok(Ok((_, Some(e)))) => Err(e),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "aio session dropped the request",
+            )),
+        }
+    }
 }
 
 impl Session {
@@ -70,35 +157,9 @@ impl Session {
             // thread to a specific core
             tid_tx.send(unsafe { libc::pthread_self() }).unwrap();
 
-            let mut ctx = match Iocontext::<usize, BytesMut, BytesMut>::new(max_queue_depth) {
-                Ok(ctx) => ctx,
-                Err(e) => panic!("could not create Iocontext: {}", e),
-            };
-
-            // Using an eventfd, the kernel can notify us when there's
-            // one or more AIO results ready. See 'man eventfd'
-            match ctx.get_evfd_stream() {
-                Ok(_) => (),
-                Err(e) => panic!("get_evfd_stream failed: {}", e),
-            };
-            let evfd = ctx.evfd.as_ref().unwrap().clone();
-
-            // Add the eventfd to the file descriptors we are
-            // interested in. This will use epoll under the hood.
-            let source = AioEventFd { inner: evfd };
-            let stream = PollEvented::new(source);
-
-            let fut = AioThread {
-                rx: rx,
-                ctx: ctx,
-                stream: stream,
-                handles_pread: Slab::with_capacity(max_queue_depth),
-                handles_pwrite: Slab::with_capacity(max_queue_depth),
-
-                last_report_ts: SystemTime::now(),
-                stats: AioStats {
-                    ..Default::default()
-                },
+            let fut = match AioThread::new(rx, max_queue_depth) {
+                Ok(fut) => fut,
+                Err(e) => panic!("could not start aio loop: {}", e),
             };
             core.spawn(fut);
             core.run().unwrap();
@@ -108,6 +169,7 @@ impl Session {
 
         Ok(Session {
             inner: tx,
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
             thread: t,
             pthread: tid,
         })
@@ -116,6 +178,24 @@ impl Session {
     pub fn thread_id(&self) -> libc::pthread_t {
         self.pthread
     }
+
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            inner: self.inner.clone(),
+            id: self.id,
+        }
+    }
+
+    /// AIO without a thread of its own. The returned future submits the
+    /// requests sent through the handle and completes them, it has to
+    /// be spawned on the runtime of the thread that uses the handle so
+    /// reads never cross threads.
+    pub fn local(max_queue_depth: usize) -> io::Result<(SessionHandle, impl Future<Output = ()>)> {
+        let (tx, rx) = mpsc::channel::<Message>(max_queue_depth);
+        let fut = AioThread::new(rx, max_queue_depth)?;
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        Ok((SessionHandle { inner: tx, id }, fut))
+    }
 }
 
 struct AioThread {
@@ -131,8 +211,66 @@ struct AioThread {
     stats: AioStats,
 }
 
+impl AioThread {
+    fn new(rx: mpsc::Receiver<Message>, max_queue_depth: usize) -> io::Result<AioThread> {
+        let mut ctx =
+            Iocontext::<usize, BytesMut, BytesMut>::new(max_queue_depth).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("could not create Iocontext: {}", e),
+                )
+            })?;
+
+        // Using an eventfd, the kernel can notify us when there's
+        // one or more AIO results ready. See 'man eventfd'
+        ctx.get_evfd_stream().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("get_evfd_stream failed: {}", e),
+            )
+        })?;
+        let evfd = ctx.evfd.as_ref().unwrap().clone();
+
+        // Add the eventfd to the file descriptors we are
+        // interested in. This will use epoll under the hood.
+        let source = AioEventFd { inner: evfd };
+        let stream = PollEvented::new(source);
+
+        health().aio_started();
+        Ok(AioThread {
+            rx: rx,
+            ctx: ctx,
+            stream: stream,
+            handles_pread: Slab::with_capacity(max_queue_depth),
+            handles_pwrite: Slab::with_capacity(max_queue_depth),
+
+            last_report_ts: SystemTime::now(),
+            stats: AioStats {
+                ..Default::default()
+            },
+        })
+    }
+}
+
+// Also runs if the loop panics
+impl Drop for AioThread {
+    fn drop(&mut self) {
+        health().aio_stopped();
+    }
+}
+
+// A request the kernel completed with an error, as handed back to
+// whoever sent it
+fn failed(op: &str, e: impl fmt::Debug) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{} failed: {:?}", op, e))
+}
+
 struct HandleEntry {
+    // Keeps the file open until the kernel is done with it
+    _file: Arc<DirectFile>,
     complete: oneshot::Sender<io::Result<(BytesMut, Option<io::Error>)>>,
+    // Requests are submitted at the end of the poll that queued them
+    timestamp: Instant,
 }
 
 #[derive(Default)]
@@ -174,18 +312,21 @@ impl Future for AioThread {
                                     token,
                                     result.is_err()
                                 );
-                                match result {
+                                metrics().aio_completed();
+                                let entry = self.handles_pread.remove(token);
+                                // The requester may have gone away already
+                                let _ = match result {
                                     Ok(_) => {
-                                        let entry = self.handles_pread.remove(token); //? .unwrap();
-
-                                        //let elapsed = entry.timestamp.elapsed().expect("Time drift!");
-                                        //trace!("pread returned in {} us", ((elapsed.as_secs() * 1_000_000_000) + elapsed.subsec_nanos() as u64) / 1000);
-
-                                        //entry.complete.send(Ok((retbuf, None))).expect("Could not send AioSession response");
-                                        entry.complete.send(Ok((retbuf, None)));
+                                        let elapsed = entry.timestamp.elapsed();
+                                        latency::record(Stage::Io, elapsed);
+                                        trace!("pread returned in {} us", elapsed.as_micros());
+                                        entry.complete.send(Ok((retbuf, None)))
+                                    }
+                                    Err(e) => {
+                                        error!("pread error {:?}", e);
+                                        entry.complete.send(Ok((retbuf, Some(failed("pread", e)))))
                                     }
-                                    Err(e) => panic!("pread error {:?}", e),
-                                }
+                                };
                             }
                             IoOp::Pwrite(retbuf, token) => {
                                 trace!(
@@ -194,20 +335,26 @@ impl Future for AioThread {
                                     result.is_err()
                                 );
 
-                                match result {
-                                    Ok(_) => {
-                                        let entry = self.handles_pwrite.remove(token); //? .unwrap();
-                                        entry.complete.send(Ok((retbuf, None)));
+                                metrics().aio_completed();
+                                let entry = self.handles_pwrite.remove(token);
+                                let _ = match result {
+                                    Ok(_) => entry.complete.send(Ok((retbuf, None))),
+                                    Err(e) => {
+                                        error!("pwrite error {:?}", e);
+                                        entry.complete.send(Ok((retbuf, Some(failed("pwrite", e)))))
                                     }
-                                    Err(e) => panic!("pwrite error {:?}", e),
-                                }
+                                };
                             }
                             _ => (),
                         }
                     }
                 }
 
-                Err(e) => panic!("ctx.results failed: {:?}", e),
+                // Completions are still queued, poll again for them
+                Err(e) => {
+                    error!("ctx.results failed: {:?}", e);
+                    cx.waker().wake_by_ref();
+                }
             }
         };
 
@@ -220,8 +367,9 @@ impl Future for AioThread {
             };
 
             match msg {
-                Message::PRead(file, offset, len, buf, complete) => {
+                Message::PRead(file, offset, len, buf, complete, sent) => {
                     self.stats.curr_preads += 1;
+                    latency::record(Stage::Queue, sent.elapsed());
 
                     // The self is a Pin<&mut Self>. Obtaining mutable references to the fields
                     // will require going through DerefMut, which requires unique borrow.
@@ -236,17 +384,20 @@ impl Future for AioThread {
                     let this = &mut *self;
                     let entry = this.handles_pread.vacant_entry();
                     let key = entry.key();
-                    match this.ctx.pread(&file, buf, offset as i64, len, key) {
+                    match this.ctx.pread(&*file, buf, offset as i64, len, key) {
                         Ok(()) => {
-                            entry.insert(HandleEntry { complete: complete });
+                            metrics().aio_queued();
+                            entry.insert(HandleEntry {
+                                _file: file,
+                                complete: complete,
+                                timestamp: Instant::now(),
+                            });
                         }
                         Err((buf, _token)) => {
-                            complete
-                                .send(Ok((
-                                    buf,
-                                    Some(io::Error::new(io::ErrorKind::Other, "pread failed")),
-                                )))
-                                .expect("Could not send AioThread error response");
+                            let _ = complete.send(Ok((
+                                buf,
+                                Some(io::Error::new(io::ErrorKind::Other, "pread failed")),
+                            )));
                         }
                     };
                 }
@@ -257,17 +408,20 @@ impl Future for AioThread {
                     let this = &mut *self;
                     let entry = this.handles_pwrite.vacant_entry();
                     let key = entry.key();
-                    match this.ctx.pwrite(&file, buf, offset as i64, key) {
+                    match this.ctx.pwrite(&*file, buf, offset as i64, key) {
                         Ok(()) => {
-                            entry.insert(HandleEntry { complete: complete });
+                            metrics().aio_queued();
+                            entry.insert(HandleEntry {
+                                _file: file,
+                                complete: complete,
+                                timestamp: Instant::now(),
+                            });
                         }
                         Err((buf, _token)) => {
-                            complete
-                                .send(Ok((
-                                    buf,
-                                    Some(io::Error::new(io::ErrorKind::Other, "pread failed")),
-                                )))
-                                .expect("Could not send AioThread error response");
+                            let _ = complete.send(Ok((
+                                buf,
+                                Some(io::Error::new(io::ErrorKind::Other, "pwrite failed")),
+                            )));
                         }
                     }
                 }
@@ -281,6 +435,7 @@ impl Future for AioThread {
 
         trace!("    batch size {}", self.ctx.batched());
         while self.ctx.batched() > 0 {
+            metrics().aio_submit(self.ctx.batched());
             if let Err(e) = self.ctx.submit() {
                 panic!("batch submit failed {:?}", e);
             }
@@ -375,6 +530,8 @@ mod tests {
     use std::io;
     use std::io::Write;
     use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Instant;
 
     use aio::{Message, Session};
     use bytes::{Buf, BufMut, BytesMut, IntoBuf};
@@ -389,6 +546,12 @@ mod tests {
         assert!(session.is_ok());
     }
 
+    #[test]
+    fn test_local() {
+        let session = Session::local(512);
+        assert!(session.is_ok());
+    }
+
     // TODO: Test max queue depth
 
     #[test]
@@ -396,14 +559,16 @@ mod tests {
         env_logger::init().unwrap();
 
         let path = new_file_with_sequential_u64("pread", 1024);
-        let file = DirectFile::open(path, Mode::Open, FileAccess::Read, 4096).unwrap();
+        let file = Arc::new(DirectFile::open(path, Mode::Open, FileAccess::Read, 4096).unwrap());
 
         let session = Session::new(2).unwrap();
 
         let mut buf = BytesMut::with_capacity(512);
         unsafe { buf.set_len(512) };
         let (tx, rx) = oneshot::channel();
-        let fut = session.inner.send(Message::PRead(file, 0, 512, buf, tx));
+        let fut = session
+            .inner
+            .send(Message::PRead(file, 0, 512, buf, tx, Instant::now()));
         fut.wait();
 
         let res = rx.wait();
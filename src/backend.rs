@@ -0,0 +1,343 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::future;
+use libaio::directio::{DirectFile, FileAccess, Mode};
+use log::{trace, warn};
+use memmap::Mmap;
+use rayon::ThreadPool;
+
+use crate::aio::SessionHandle;
+use crate::latency::{self, Stage};
+use crate::overlay::align;
+
+// Largest single AIO read. Values spanning more than this are read
+// with several requests and stitched back together.
+const MAX_AIO_READ: u64 = 64 * 1024;
+
+// Padding for values written with O_DIRECT
+static ZEROS: [u8; 512] = [0; 512];
+
+/// How values are read from and written to `protostore.data`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadBackend {
+    // O_DIRECT through libaio, bypasses the page cache
+    Direct,
+    // Plain pread and pwrite on a thread pool
+    Buffered,
+    // Reads from a mapping of the file, writes as Buffered
+    Mmap,
+}
+
+impl FromStr for ReadBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReadBackend, String> {
+        match s {
+            "direct" => Ok(ReadBackend::Direct),
+            "buffered" => Ok(ReadBackend::Buffered),
+            "mmap" => Ok(ReadBackend::Mmap),
+            other => Err(format!("unknown read backend {:?}", other)),
+        }
+    }
+}
+
+/// `protostore.data` opened for one of the read backends.
+#[derive(Debug)]
+pub enum DataFile {
+    Direct(Arc<DirectFile>),
+    Buffered(Arc<File>),
+    // The mapping covers the file as it was on open, values written
+    // after that are read from the file
+    Mmap(Arc<File>, Option<Mmap>),
+}
+
+impl DataFile {
+    /// Opens `path` for `backend`, read-only unless `writable`.
+    /// Filesystems without O_DIRECT support, like tmpfs, get the
+    /// buffered backend instead.
+    pub fn open(path: &Path, backend: ReadBackend, writable: bool) -> Result<DataFile, io::Error> {
+        match backend {
+            ReadBackend::Direct => {
+                let access = if writable {
+                    FileAccess::ReadWrite
+                } else {
+                    FileAccess::Read
+                };
+                match DirectFile::open(path, Mode::Open, access, 4096) {
+                    Ok(file) => Ok(DataFile::Direct(Arc::new(file))),
+                    Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                        warn!(
+                            "{:?} does not support O_DIRECT, falling back to buffered reads",
+                            path
+                        );
+                        DataFile::open(path, ReadBackend::Buffered, writable)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            ReadBackend::Buffered => {
+                let file = OpenOptions::new().read(true).write(writable).open(path)?;
+                Ok(DataFile::Buffered(Arc::new(file)))
+            }
+            ReadBackend::Mmap => {
+                let file = OpenOptions::new().read(true).write(writable).open(path)?;
+                // Empty files can't be mapped
+                let map = if file.metadata()?.len() > 0 {
+                    Some(unsafe { Mmap::map(&file)? })
+                } else {
+                    None
+                };
+                Ok(DataFile::Mmap(Arc::new(file), map))
+            }
+        }
+    }
+
+    pub fn backend(&self) -> ReadBackend {
+        match self {
+            DataFile::Direct(_) => ReadBackend::Direct,
+            DataFile::Buffered(_) => ReadBackend::Buffered,
+            DataFile::Mmap(_, _) => ReadBackend::Mmap,
+        }
+    }
+}
+
+/// Reads and writes values in a `DataFile` with the backend it was
+/// opened for.
+#[derive(Clone)]
+pub struct DataIo {
+    aio: SessionHandle,
+    // Runs the blocking reads and writes of the other backends
+    pool: Arc<ThreadPool>,
+}
+
+impl DataIo {
+    pub fn new(aio: SessionHandle, pool: Arc<ThreadPool>) -> DataIo {
+        DataIo { aio, pool }
+    }
+
+    /// Id of the AIO session direct reads and writes go through.
+    pub fn aio_session(&self) -> usize {
+        self.aio.id()
+    }
+
+    pub async fn read(&self, data: &DataFile, offset: u64, len: u32) -> Result<Bytes, io::Error> {
+        match data {
+            DataFile::Direct(file) => self.read_direct(file, offset, len).await,
+            DataFile::Buffered(file) => self.read_blocking(file, offset, len).await,
+            DataFile::Mmap(_, Some(map)) if offset + len as u64 <= map.len() as u64 => {
+                let start = Instant::now();
+                let value = Bytes::from(&map[offset as usize..(offset + len as u64) as usize]);
+                latency::record(Stage::Io, start.elapsed());
+                Ok(value)
+            }
+            DataFile::Mmap(file, _) => self.read_blocking(file, offset, len).await,
+        }
+    }
+
+    /// Writes `value` at `offset`, which must be aligned for O_DIRECT.
+    pub async fn write(&self, data: &DataFile, offset: u64, value: &[u8]) -> Result<(), io::Error> {
+        match data {
+            DataFile::Direct(file) => {
+                let aligned_len = cmp::max(512, align(value.len() as u64) as usize);
+                let mut buf = BytesMut::with_capacity(aligned_len);
+                buf.extend_from_slice(value);
+                buf.extend_from_slice(&ZEROS[..aligned_len - value.len()]);
+                self.aio.pwrite(file.clone(), offset as usize, buf).await?;
+                Ok(())
+            }
+            DataFile::Buffered(file) | DataFile::Mmap(file, _) => {
+                let file = file.clone();
+                let value = value.to_vec();
+                self.blocking(move || file.write_all_at(&value, offset))
+                    .await
+            }
+        }
+    }
+
+    /// Flushes written values to the device, so a record committed
+    /// after this never points at a value lost in a crash.
+    pub async fn sync(&self, data: &DataFile) -> Result<(), io::Error> {
+        match data {
+            DataFile::Direct(file) => {
+                // O_DIRECT skips the page cache, but neither the drive's
+                // write cache nor the file size the appends grew
+                let file = file.clone();
+                self.blocking(move || {
+                    if unsafe { libc::fdatasync(file.as_raw_fd()) } == 0 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
+                })
+                .await
+            }
+            DataFile::Buffered(file) | DataFile::Mmap(file, _) => {
+                let file = file.clone();
+                self.blocking(move || file.sync_data()).await
+            }
+        }
+    }
+
+    async fn read_direct(
+        &self,
+        file: &Arc<DirectFile>,
+        offset: u64,
+        len: u32,
+    ) -> Result<Bytes, io::Error> {
+        let aligned_offset = offset - (offset % 512);
+        let pad_left = offset - aligned_offset;
+        let padded = pad_left + len as u64;
+        let aligned_len = cmp::max(512, padded + 512 - (padded as u64 % 512));
+
+        let reads = (0..aligned_len)
+            .step_by(MAX_AIO_READ as usize)
+            .map(|chunk_offset| {
+                let chunk_len = cmp::min(MAX_AIO_READ, aligned_len - chunk_offset) as usize;
+                let mut buf = BytesMut::with_capacity(chunk_len);
+                unsafe { buf.set_len(chunk_len) };
+                self.aio.pread(
+                    file.clone(),
+                    (aligned_offset + chunk_offset) as usize,
+                    chunk_len,
+                    buf,
+                )
+            });
+        let mut chunks = future::try_join_all(reads).await?;
+        trace!("Read {} bytes in {} chunks", aligned_len, chunks.len());
+
+        let value = if chunks.len() == 1 {
+            chunks.pop().unwrap()
+        } else {
+            let mut value = BytesMut::with_capacity(aligned_len as usize);
+            for chunk in chunks {
+                value.extend_from_slice(&chunk);
+            }
+            value
+        };
+
+        Ok(value
+            .freeze()
+            .slice(pad_left as usize, (pad_left + len as u64) as usize))
+    }
+
+    async fn read_blocking(
+        &self,
+        file: &Arc<File>,
+        offset: u64,
+        len: u32,
+    ) -> Result<Bytes, io::Error> {
+        let file = file.clone();
+        self.blocking(move || {
+            let start = Instant::now();
+            let mut buf = vec![0; len as usize];
+            file.read_exact_at(&mut buf, offset)?;
+            latency::record(Stage::Io, start.elapsed());
+            Ok(Bytes::from(buf))
+        })
+        .await
+    }
+
+    /// Runs `f` on the blocking pool, for IO that would otherwise stall
+    /// every connection on the calling thread.
+    pub async fn blocking<T, F>(&self, f: F) -> Result<T, io::Error>
+    where
+        F: FnOnce() -> Result<T, io::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let _ = tx.send(f());
+        });
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "blocking io pool dropped the request",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use rayon::ThreadPoolBuilder;
+    use tempdir::TempDir;
+
+    use super::{DataFile, DataIo, ReadBackend};
+    use crate::aio::Session;
+
+    fn write_data(path: &Path) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(b"abcdefgh").unwrap();
+    }
+
+    fn data_io(session: &Session) -> DataIo {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        DataIo::new(session.handle(), Arc::new(pool))
+    }
+
+    #[test]
+    fn parse_backend() {
+        assert_eq!(Ok(ReadBackend::Mmap), "mmap".parse());
+        assert!("odirect".parse::<ReadBackend>().is_err());
+    }
+
+    #[test]
+    fn buffered() {
+        let tmp = TempDir::new("backend").unwrap();
+        let path = tmp.path().join("protostore.data");
+        write_data(&path);
+
+        let session = Session::new(4).unwrap();
+        let io = data_io(&session);
+        let data = DataFile::open(&path, ReadBackend::Buffered, true).unwrap();
+
+        assert_eq!(
+            Bytes::from(&b"cdef"[..]),
+            block_on(io.read(&data, 2, 4)).unwrap()
+        );
+        block_on(io.write(&data, 512, b"xyz")).unwrap();
+        assert_eq!(
+            Bytes::from(&b"xyz"[..]),
+            block_on(io.read(&data, 512, 3)).unwrap()
+        );
+    }
+
+    #[test]
+    fn mmap_reads_past_mapping() {
+        let tmp = TempDir::new("backend").unwrap();
+        let path = tmp.path().join("protostore.data");
+        write_data(&path);
+
+        let session = Session::new(4).unwrap();
+        let io = data_io(&session);
+        let data = DataFile::open(&path, ReadBackend::Mmap, true).unwrap();
+
+        assert_eq!(
+            Bytes::from(&b"cdef"[..]),
+            block_on(io.read(&data, 2, 4)).unwrap()
+        );
+        block_on(io.write(&data, 512, b"xyz")).unwrap();
+        assert_eq!(
+            Bytes::from(&b"xyz"[..]),
+            block_on(io.read(&data, 512, 3)).unwrap()
+        );
+    }
+}
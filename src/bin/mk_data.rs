@@ -1,8 +1,11 @@
 // time cargo run --bin mk_data -- --path=/mnt/data/ --num-cookies=250000000 --min-size 4 --max-size 1024
 
-use std::fs::OpenOptions;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bytes::{ByteOrder, LittleEndian};
 use clap::{App, Arg};
@@ -12,6 +15,10 @@ use rand::{Rng, SeedableRng};
 
 use rayon::prelude::*;
 
+use protostore::{hash_key, FLAG_COMPRESSED, FLAG_KEYED};
+
+const COMPRESSION_LEVEL: i32 = 3;
+
 fn main() {
     let matches = App::new("mk_data")
         .arg(
@@ -25,9 +32,13 @@ fn main() {
             Arg::with_name("cookies")
                 .long("num-cookies")
                 .takes_value(true)
-                .required(true)
+                .required_unless("keys")
                 .help("Number of cookies to write in datafiles"),
         )
+        .arg(Arg::with_name("keys").long("keys").takes_value(true).help(
+            "File with one key per line. Keys are hashed into uuids \
+                     instead of generating random ones",
+        ))
         .arg(
             Arg::with_name("min_size")
                 .long("min-size")
@@ -45,6 +56,24 @@ fn main() {
                      Values will be randomly distributed between min and max",
                 ),
         )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .takes_value(true)
+                .help("Seconds from now after which every cookie expires"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .help("Compress values with a zstd dictionary trained on them"),
+        )
+        .arg(
+            Arg::with_name("dict_size")
+                .long("dict-size")
+                .takes_value(true)
+                .default_value("112640")
+                .help("Maximum size of the compression dictionary, in bytes"),
+        )
         .get_matches();
 
     let path = matches.value_of("path").unwrap();
@@ -52,28 +81,63 @@ fn main() {
     let mut toc_uuids_path = PathBuf::from(path);
     let mut toc_offsets_path = PathBuf::from(path);
     let mut toc_lens_path = PathBuf::from(path);
+    let mut toc_checksums_path = PathBuf::from(path);
+    let mut toc_flags_path = PathBuf::from(path);
+    let mut toc_expiry_path = PathBuf::from(path);
+    let mut dict_path = PathBuf::from(path);
     let mut data_path = PathBuf::from(path);
 
     toc_uuids_path.push("protostore.toc.uuids");
     toc_offsets_path.push("protostore.toc.offsets");
     toc_lens_path.push("protostore.toc.lengths");
+    toc_checksums_path.push("protostore.toc.checksums");
+    toc_flags_path.push("protostore.toc.flags");
+    toc_expiry_path.push("protostore.toc.expiry");
+    dict_path.push("protostore.dict");
     data_path.push("protostore.data");
 
-    let num_cookies = matches
-        .value_of("cookies")
-        .unwrap()
-        .parse::<u64>()
-        .expect("Could not parse cookies into u64");
+    // Either random uuids, or hashes of the given keys. keys[i] is the
+    // key for uuids[i] when keys are used.
+    let (mut uuids, keys) = match matches.value_of("keys") {
+        Some(keys_path) => read_keys(keys_path),
+        None => {
+            let num_cookies = matches
+                .value_of("cookies")
+                .unwrap()
+                .parse::<u64>()
+                .expect("Could not parse cookies into u64");
+            let uuids = (0..num_cookies)
+                .into_par_iter()
+                .map(|_| *uuid::Uuid::new_v4().as_bytes())
+                .collect::<Vec<[u8; 16]>>();
+            (uuids, vec![])
+        }
+    };
+    let num_cookies = uuids.len() as u64;
     let min_size = matches
         .value_of("min_size")
         .unwrap()
-        .parse::<u16>()
+        .parse::<u32>()
         .expect("Could not parse min-size");
     let max_size = matches
         .value_of("max_size")
         .unwrap()
-        .parse::<u16>()
+        .parse::<u32>()
         .expect("Could not parse max-size");
+    let expires_at = matches.value_of("ttl").map(|ttl| {
+        let ttl = ttl.parse::<u64>().expect("Could not parse ttl");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time drift!")
+            .as_secs();
+        now + ttl
+    });
+    let compress = matches.is_present("compress");
+    let dict_size = matches
+        .value_of("dict_size")
+        .unwrap()
+        .parse::<usize>()
+        .expect("Could not parse dict-size");
 
     let mut opts = OpenOptions::new();
     opts.write(true).create(true).truncate(true);
@@ -81,6 +145,7 @@ fn main() {
     let mut toc_uuids_file = opts.open(toc_uuids_path).unwrap();
     let mut toc_offsets_file = opts.open(toc_offsets_path).unwrap();
     let mut toc_lens_file = opts.open(toc_lens_path).unwrap();
+    let mut toc_checksums_file = opts.open(toc_checksums_path).unwrap();
     let mut data_file = opts.open(data_path).unwrap();
 
     let mut rng = SmallRng::from_entropy();
@@ -93,31 +158,79 @@ fn main() {
 
     let mut toc_uuids_buf: Vec<u8> = Vec::with_capacity(16 * num_cookies as usize);
     let mut toc_offsets_buf: Vec<u8> = Vec::with_capacity(8 * num_cookies as usize);
-    let mut toc_lens_buf: Vec<u8> = Vec::with_capacity(2 * num_cookies as usize);
+    let mut toc_lens_buf: Vec<u8> = Vec::with_capacity(4 * num_cookies as usize);
+    let mut toc_checksums_buf: Vec<u8> = Vec::with_capacity(4 * num_cookies as usize);
+    let mut toc_flags_buf: Vec<u8> = Vec::with_capacity(num_cookies as usize);
 
     let mut lens: Vec<u64> = Vec::with_capacity(num_cookies as usize);
 
-    let mut uuids = (0..num_cookies)
-        .into_par_iter()
-        .map(|_| *uuid::Uuid::new_v4().as_bytes())
-        .collect::<Vec<[u8; 16]>>();
+    if keys.is_empty() {
+        println!("Sorting uuids");
+        uuids.sort();
+    }
+
+    // Every value is a prefix of the same dummy payload, so it's built
+    // up front to checksum values while writing the TOC.
+    let mut dummies: Vec<u8> = vec![];
 
-    println!("Sorting uuids");
-    uuids.sort();
+    for i in 0..max_size {
+        let mut encoded_len = [0; 8];
+        LittleEndian::write_u64(&mut encoded_len, i as u64);
+        dummies.extend(encoded_len.iter());
+    }
+
+    // Values only depend on their length, so each length is compressed
+    // once. Lengths that don't shrink are stored uncompressed.
+    let mut compressed: HashMap<u32, Vec<u8>> = HashMap::new();
+    if compress {
+        println!("Training compression dictionary");
+        let samples = (min_size..max_size)
+            .map(|len| &dummies[0..(len as usize)])
+            .collect::<Vec<&[u8]>>();
+        let dict = zstd::dict::from_samples(&samples, dict_size)
+            .expect("Could not train compression dictionary");
+        opts.open(dict_path)
+            .unwrap()
+            .write_all(&dict)
+            .expect("Could not write dictionary to file");
+
+        let mut compressor = zstd::block::Compressor::with_dict(dict);
+        for len in min_size..max_size {
+            let value = &dummies[0..(len as usize)];
+            let frame = compressor
+                .compress(value, COMPRESSION_LEVEL)
+                .expect("Could not compress value");
+            if 4 + frame.len() < value.len() {
+                let mut stored = vec![0; 4];
+                LittleEndian::write_u32(&mut stored, len);
+                stored.extend_from_slice(&frame);
+                compressed.insert(len, stored);
+            }
+        }
+        println!(
+            "Compressing {} of {} value lengths",
+            compressed.len(),
+            max_size - min_size
+        );
+    }
 
     println!("Writing Table of Contents to in-memory buffers");
     let mut offset: u64 = 0;
-    for uuid in uuids {
-        let len = rng.gen_range(min_size as u16, max_size as u16);
+    for (i, uuid) in uuids.iter().enumerate() {
+        let len = rng.gen_range(min_size, max_size);
         lens.push(len as u64);
 
+        let (flags, value) = stored_record(&compressed, &dummies, &keys, i, len);
+
         let mut encoded_offset = [0; 8];
-        let mut encoded_len = [0; 2];
+        let mut encoded_len = [0; 4];
+        let mut encoded_checksum = [0; 4];
         LittleEndian::write_u64(&mut encoded_offset, offset);
-        LittleEndian::write_u16(&mut encoded_len, len as u16);
+        LittleEndian::write_u32(&mut encoded_len, value.len() as u32);
+        LittleEndian::write_u32(&mut encoded_checksum, crc32c::crc32c(&value));
 
         toc_uuids_buf
-            .write_all(&uuid)
+            .write_all(uuid)
             .expect("Could not write to toc_buf");
         toc_offsets_buf
             .write_all(&encoded_offset)
@@ -125,8 +238,12 @@ fn main() {
         toc_lens_buf
             .write_all(&encoded_len)
             .expect("Could not write to toc_buf");
+        toc_checksums_buf
+            .write_all(&encoded_checksum)
+            .expect("Could not write to toc_buf");
+        toc_flags_buf.push(flags);
 
-        offset += len as u64;
+        offset += value.len() as u64;
     }
 
     println!("Writing Table of Contents to disk");
@@ -139,27 +256,36 @@ fn main() {
     toc_lens_file
         .write_all(&toc_lens_buf)
         .expect("Could not write buffer to file");
+    toc_checksums_file
+        .write_all(&toc_checksums_buf)
+        .expect("Could not write buffer to file");
+    if compress || !keys.is_empty() {
+        opts.open(toc_flags_path)
+            .unwrap()
+            .write_all(&toc_flags_buf)
+            .expect("Could not write buffer to file");
+    }
+    if let Some(expires_at) = expires_at {
+        let mut toc_expiry_buf: Vec<u8> = vec![0; 8 * num_cookies as usize];
+        for encoded in toc_expiry_buf.chunks_mut(8) {
+            LittleEndian::write_u64(encoded, expires_at);
+        }
+        opts.open(toc_expiry_path)
+            .unwrap()
+            .write_all(&toc_expiry_buf)
+            .expect("Could not write buffer to file");
+    }
 
-    let total_entries: u64 = lens.iter().sum();
-    let total_bytes = total_entries;
+    let total_bytes = offset;
     let total_gb = total_bytes / 1024 / 1024 / 1024;
     println!(
         "Creating data file. Need to write {} bytes, {} GB",
         total_bytes, total_gb
     );
 
-    let max_len = lens.iter().max().unwrap();
-    let mut dummies: Vec<u8> = vec![];
-
-    for i in 0..*max_len {
-        let mut encoded_len = [0; 8];
-        LittleEndian::write_u64(&mut encoded_len, i as u64);
-        dummies.extend(encoded_len.iter());
-    }
-
     let mut n = 0;
     let mut written_bytes = 0;
-    for chunk in lens.chunks(100_000) {
+    for (chunk_idx, chunk) in lens.chunks(100_000).enumerate() {
         println!(
             "{} of {}, {} GB of {} GB",
             n,
@@ -171,13 +297,74 @@ fn main() {
 
         let chunk_sum: u64 = chunk.iter().sum();
         let mut data_buf = Vec::with_capacity(chunk_sum as usize);
-        for len in chunk {
-            data_buf.extend_from_slice(&dummies[0..(*len as usize)]);
+        for (i, len) in chunk.iter().enumerate() {
+            let index = chunk_idx * 100_000 + i;
+            let (_, value) = stored_record(&compressed, &dummies, &keys, index, *len as u32);
+            data_buf.extend_from_slice(&value);
         }
         data_file
             .write_all(&data_buf)
             .expect("Could not write data_buf to data_file");
         data_file.sync_all().expect("fsync failed");
-        written_bytes += chunk_sum;
+        written_bytes += data_buf.len() as u64;
     }
 }
+
+// Bytes stored in the data file for the record at `index` with a value
+// of `len` bytes, and the flags describing them.
+fn stored_record<'a>(
+    compressed: &'a HashMap<u32, Vec<u8>>,
+    dummies: &'a [u8],
+    keys: &[Vec<u8>],
+    index: usize,
+    len: u32,
+) -> (u8, Cow<'a, [u8]>) {
+    let (flags, value): (u8, &[u8]) = match compressed.get(&len) {
+        Some(stored) => (FLAG_COMPRESSED, stored),
+        None => (0, &dummies[0..(len as usize)]),
+    };
+
+    match keys.get(index) {
+        Some(key) => {
+            let mut record = vec![0; 2];
+            LittleEndian::write_u16(&mut record, key.len() as u16);
+            record.extend_from_slice(key);
+            record.extend_from_slice(value);
+            (flags | FLAG_KEYED, Cow::Owned(record))
+        }
+        None => (flags, Cow::Borrowed(value)),
+    }
+}
+
+// Reads one key per line and returns the hashes sorted, along with the
+// key each hash came from. Two different keys with the same hash can't
+// be stored, so that aborts.
+fn read_keys(path: &str) -> (Vec<[u8; 16]>, Vec<Vec<u8>>) {
+    let contents = fs::read(path).expect("Could not read keys file");
+    let mut keyed = contents
+        .split(|b| *b == b'\n')
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            if key.len() > u16::max_value() as usize {
+                panic!("Key of {} bytes is too long", key.len());
+            }
+            (hash_key(key), key.to_vec())
+        })
+        .collect::<Vec<([u8; 16], Vec<u8>)>>();
+
+    println!("Sorting {} hashed keys", keyed.len());
+    keyed.par_sort();
+    keyed.dedup();
+
+    for pair in keyed.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            panic!(
+                "Keys {:?} and {:?} hash to the same uuid",
+                String::from_utf8_lossy(&pair[0].1),
+                String::from_utf8_lossy(&pair[1].1)
+            );
+        }
+    }
+
+    keyed.into_iter().unzip()
+}
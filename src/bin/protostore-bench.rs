@@ -0,0 +1,592 @@
+// cargo run --release --bin protostore-bench -- --path=./db --connections=16 --pipeline=8
+//
+// Pass --mode=redis to benchmark a redis server loaded with the same
+// uuids as keys, for comparison.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use clap::{App, Arg};
+use futures::channel::oneshot;
+use hdrhistogram::Histogram;
+use memmap::Mmap;
+use protostore_client::{Frame, Response, Status};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::timer::{delay, Timeout};
+
+// Microseconds, up to a minute
+const MAX_LATENCY: u64 = 60_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Protostore,
+    Redis,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Read,
+    Write,
+}
+
+struct Options {
+    mode: Mode,
+    addr: SocketAddr,
+    pipeline: usize,
+    // Time between requests of one connection, None for closed loop
+    interval: Option<Duration>,
+    write_ratio: f64,
+    value: Vec<u8>,
+    keys: Keys,
+}
+
+// Picks the uuids to request from the table of contents
+struct Keys {
+    uuids: Mmap,
+    zipf: Option<Zipf>,
+    miss_ratio: f64,
+}
+
+impl Keys {
+    fn len(&self) -> u64 {
+        self.uuids.len() as u64 / 16
+    }
+
+    fn next(&self, rng: &mut SmallRng) -> [u8; 16] {
+        let mut uuid = [0; 16];
+        if rng.gen::<f64>() < self.miss_ratio {
+            // Random uuids are as good as certain to be missing
+            rng.fill(&mut uuid);
+            return uuid;
+        }
+        let index = match self.zipf {
+            Some(ref zipf) => zipf.sample(rng) - 1,
+            None => rng.gen_range(0, self.len()),
+        } as usize;
+        uuid.copy_from_slice(&self.uuids[index * 16..index * 16 + 16]);
+        uuid
+    }
+}
+
+// Zipf distributed ranks from 1 to n, by rejection-inversion as in
+// "Rejection-inversion to generate variates from monotone discrete
+// distributions" (Hörmann and Derflinger). Constant time and memory,
+// where a table of the distribution would take gigabytes.
+struct Zipf {
+    exponent: f64,
+    n: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    s: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, exponent: f64) -> Zipf {
+        let mut zipf = Zipf {
+            exponent,
+            n: n as f64,
+            h_integral_x1: 0.0,
+            h_integral_n: 0.0,
+            s: 0.0,
+        };
+        zipf.h_integral_x1 = zipf.h_integral(1.5) - 1.0;
+        zipf.h_integral_n = zipf.h_integral(zipf.n + 0.5);
+        zipf.s = 2.0 - zipf.h_integral_inverse(zipf.h_integral(2.5) - zipf.h(2.0));
+        zipf
+    }
+
+    fn sample(&self, rng: &mut SmallRng) -> u64 {
+        loop {
+            let u = self.h_integral_n + rng.gen::<f64>() * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inverse(u);
+            let k = (x + 0.5).max(1.0).min(self.n).floor();
+            if k - x <= self.s || u >= self.h_integral(k + 0.5) - self.h(k) {
+                return k as u64;
+            }
+        }
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        (-self.exponent * x.ln()).exp()
+    }
+
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        helper2((1.0 - self.exponent) * log_x) * log_x
+    }
+
+    fn h_integral_inverse(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.exponent)).max(-1.0);
+        (helper1(t) * x).exp()
+    }
+}
+
+// ln(1 + x) / x, accurate near 0
+fn helper1(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.ln_1p() / x
+    } else {
+        1.0 - x * (0.5 - x * (1.0 / 3.0 - 0.25 * x))
+    }
+}
+
+// (e^x - 1) / x, accurate near 0
+fn helper2(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.exp_m1() / x
+    } else {
+        1.0 + x * 0.5 * (1.0 + x / 3.0 * (1.0 + 0.25 * x))
+    }
+}
+
+struct Stats {
+    reads: Histogram<u64>,
+    writes: Histogram<u64>,
+    bytes: u64,
+    misses: u64,
+    errors: u64,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            reads: Histogram::new_with_bounds(1, MAX_LATENCY, 3).unwrap(),
+            writes: Histogram::new_with_bounds(1, MAX_LATENCY, 3).unwrap(),
+            bytes: 0,
+            misses: 0,
+            errors: 0,
+        }
+    }
+
+    fn add(&mut self, other: &Stats) {
+        self.reads.add(&other.reads).unwrap();
+        self.writes.add(&other.writes).unwrap();
+        self.bytes += other.bytes;
+        self.misses += other.misses;
+        self.errors += other.errors;
+    }
+}
+
+enum Reply {
+    Value(usize),
+    Missing,
+    Error,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("protostore-bench")
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .required(true)
+                .help("Dataset directory to take the uuids to request from"),
+        )
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .default_value("127.0.0.1:8080")
+                .help("Address of the server"),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .possible_values(&["protostore", "redis"])
+                .default_value("protostore")
+                .help("Protocol to speak, redis sends GET and SET with the uuid as key"),
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .takes_value(true)
+                .default_value("10")
+                .help("Concurrent connections"),
+        )
+        .arg(
+            Arg::with_name("pipeline")
+                .long("pipeline")
+                .takes_value(true)
+                .default_value("1")
+                .help("Requests in flight on each connection"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds to run for"),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .help("Requests per second over all connections, runs open loop when set"),
+        )
+        .arg(
+            Arg::with_name("distribution")
+                .long("distribution")
+                .takes_value(true)
+                .possible_values(&["uniform", "zipf"])
+                .default_value("uniform")
+                .help("How keys are picked from the table of contents"),
+        )
+        .arg(
+            Arg::with_name("zipf_exponent")
+                .long("zipf-exponent")
+                .takes_value(true)
+                .default_value("0.99")
+                .help("Skew of the zipf distribution"),
+        )
+        .arg(
+            Arg::with_name("miss_ratio")
+                .long("miss-ratio")
+                .takes_value(true)
+                .default_value("0")
+                .help("Fraction of requests for uuids that aren't in the dataset"),
+        )
+        .arg(
+            Arg::with_name("write_ratio")
+                .long("write-ratio")
+                .takes_value(true)
+                .default_value("0")
+                .help("Fraction of requests that are writes"),
+        )
+        .arg(
+            Arg::with_name("value_size")
+                .long("value-size")
+                .takes_value(true)
+                .default_value("1024")
+                .help("Bytes in each written value"),
+        )
+        .get_matches();
+
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    let connections: usize = matches.value_of("connections").unwrap().parse()?;
+    let duration = Duration::from_secs(matches.value_of("duration").unwrap().parse()?);
+    let rate = match matches.value_of("rate") {
+        Some(rate) => Some(rate.parse::<f64>()?),
+        None => None,
+    };
+
+    let uuids_path = path.join("protostore.toc.uuids");
+    let uuids = match File::open(&uuids_path).and_then(|file| unsafe { Mmap::map(&file) }) {
+        Ok(uuids) => uuids,
+        Err(e) => {
+            println!("Could not read {:?}: {}", uuids_path, e);
+            process::exit(1);
+        }
+    };
+    let num_keys = uuids.len() as u64 / 16;
+    if num_keys == 0 {
+        println!("No uuids in {:?}", uuids_path);
+        process::exit(1);
+    }
+    println!("Read table of contents with {} entries", num_keys);
+
+    let zipf = if matches.value_of("distribution") == Some("zipf") {
+        let exponent = matches.value_of("zipf_exponent").unwrap().parse()?;
+        Some(Zipf::new(num_keys, exponent))
+    } else {
+        None
+    };
+    let value_size: usize = matches.value_of("value_size").unwrap().parse()?;
+    let options = Arc::new(Options {
+        mode: match matches.value_of("mode") {
+            Some("redis") => Mode::Redis,
+            _ => Mode::Protostore,
+        },
+        addr: matches.value_of("addr").unwrap().parse()?,
+        pipeline: matches
+            .value_of("pipeline")
+            .unwrap()
+            .parse::<usize>()?
+            .max(1),
+        // Each connection sends its share of the rate
+        interval: rate.map(|rate| Duration::from_secs_f64(connections as f64 / rate)),
+        write_ratio: matches.value_of("write_ratio").unwrap().parse()?,
+        value: (0..value_size).map(|i| i as u8).collect(),
+        keys: Keys {
+            uuids,
+            zipf,
+            miss_ratio: matches.value_of("miss_ratio").unwrap().parse()?,
+        },
+    });
+
+    println!(
+        "Running {} {} connections with {} requests in flight each for {:?}, {}",
+        connections,
+        matches.value_of("mode").unwrap(),
+        options.pipeline,
+        duration,
+        match rate {
+            Some(rate) => format!("open loop at {} requests/s", rate),
+            None => "closed loop".to_owned(),
+        }
+    );
+
+    let started = Instant::now();
+    let deadline = started + duration;
+    let mut results = vec![];
+    for _ in 0..connections {
+        let (tx, rx) = oneshot::channel();
+        let options = options.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(run_connection(options, deadline).await);
+        });
+        results.push(rx);
+    }
+
+    let mut stats = Stats::new();
+    let mut failed = 0;
+    for result in results {
+        match result.await {
+            Ok(Ok(connection)) => stats.add(&connection),
+            Ok(Err(e)) => {
+                println!("Connection failed: {}", e);
+                failed += 1;
+            }
+            Err(_) => failed += 1,
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let requests = stats.reads.len() + stats.writes.len();
+    println!("============================");
+    println!("Connections: {} ({} failed)", connections, failed);
+    println!("Runtime: {:.2} s", elapsed);
+    println!("Total requests: {}", requests);
+    println!("Avg rps: {:.2}", requests as f64 / elapsed);
+    println!("Misses: {}, errors: {}", stats.misses, stats.errors);
+    println!(
+        "Bytes transferred: {:.2} MB, {:.2} MB/s",
+        stats.bytes as f64 / 1024.0 / 1024.0,
+        stats.bytes as f64 / 1024.0 / 1024.0 / elapsed
+    );
+    print_latencies("Read", &stats.reads);
+    print_latencies("Write", &stats.writes);
+    Ok(())
+}
+
+fn print_latencies(name: &str, histogram: &Histogram<u64>) {
+    if histogram.len() == 0 {
+        return;
+    }
+    println!(
+        "{} latencies: 50th: {}us 75th: {}us 90th: {}us 95th: {}us 99th: {}us 99.9th: {}us \
+         max: {}us",
+        name,
+        histogram.value_at_quantile(0.5),
+        histogram.value_at_quantile(0.75),
+        histogram.value_at_quantile(0.9),
+        histogram.value_at_quantile(0.95),
+        histogram.value_at_quantile(0.99),
+        histogram.value_at_quantile(0.999),
+        histogram.max()
+    );
+}
+
+// Keeps up to `pipeline` requests in flight until the deadline, then
+// waits for the ones still out. In open loop requests are sent on a
+// schedule and their latency counts from when they were due, so a
+// server falling behind can't hide it by slowing the client down.
+async fn run_connection(options: Arc<Options>, deadline: Instant) -> io::Result<Stats> {
+    let mut stream = TcpStream::connect(&options.addr).await?;
+    let mut rng = SmallRng::from_entropy();
+    let mut stats = Stats::new();
+
+    // Responses come back in the order requests were sent
+    let mut inflight: VecDeque<(u32, Op, Instant)> = VecDeque::with_capacity(options.pipeline);
+    let mut next_id: u32 = 0;
+    let mut next_send = Instant::now();
+    let mut requests = BytesMut::with_capacity(4096);
+    let mut responses = BytesMut::with_capacity(64 * 1024);
+    let mut chunk = vec![0; 64 * 1024];
+
+    loop {
+        let now = Instant::now();
+        while now < deadline && inflight.len() < options.pipeline {
+            let scheduled = match options.interval {
+                Some(_) if next_send > now => break,
+                Some(interval) => {
+                    let scheduled = next_send;
+                    next_send += interval;
+                    scheduled
+                }
+                None => now,
+            };
+            let op = if rng.gen::<f64>() < options.write_ratio {
+                Op::Write
+            } else {
+                Op::Read
+            };
+            let uuid = options.keys.next(&mut rng);
+            encode_request(
+                options.mode,
+                &mut requests,
+                op,
+                next_id,
+                &uuid,
+                &options.value,
+            );
+            inflight.push_back((next_id, op, scheduled));
+            next_id = next_id.wrapping_add(1);
+        }
+        if !requests.is_empty() {
+            stream.write_all(&requests).await?;
+            requests.clear();
+        }
+
+        if inflight.is_empty() {
+            if now >= deadline {
+                return Ok(stats);
+            }
+            delay(next_send).await;
+            continue;
+        }
+
+        // In open loop, stop waiting for responses when the next
+        // request is due. Past the deadline nothing more is due.
+        let read = match options.interval {
+            Some(_) if now < deadline && inflight.len() < options.pipeline => {
+                let wait = if next_send > now {
+                    next_send - now
+                } else {
+                    Duration::from_millis(0)
+                };
+                match Timeout::new(stream.read(&mut chunk[..]), wait).await {
+                    Ok(read) => read?,
+                    Err(_) => continue,
+                }
+            }
+            _ => stream.read(&mut chunk[..]).await?,
+        };
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection",
+            ));
+        }
+        responses.extend_from_slice(&chunk[..read]);
+
+        let received = Instant::now();
+        while let Some((id, reply)) = decode_reply(options.mode, &mut responses)? {
+            let (expected, op, scheduled) = match inflight.pop_front() {
+                Some(request) => request,
+                None => return Err(invalid_data("response to a request that wasn't sent")),
+            };
+            if options.mode == Mode::Protostore && id != expected {
+                return Err(invalid_data("responses out of order"));
+            }
+
+            let latency = received.duration_since(scheduled).as_micros() as u64;
+            let histogram = match op {
+                Op::Read => &mut stats.reads,
+                Op::Write => &mut stats.writes,
+            };
+            histogram.saturating_record(latency.max(1));
+            match reply {
+                // Empty reads are how protostore says not found
+                Reply::Value(0) if op == Op::Read => stats.misses += 1,
+                Reply::Value(len) => stats.bytes += len as u64,
+                Reply::Missing => stats.misses += 1,
+                Reply::Error => stats.errors += 1,
+            }
+        }
+    }
+}
+
+fn encode_request(mode: Mode, buf: &mut BytesMut, op: Op, id: u32, uuid: &[u8; 16], value: &[u8]) {
+    match (mode, op) {
+        (Mode::Protostore, Op::Read) => Frame::Read { id, uuid: *uuid }.encode(buf),
+        (Mode::Protostore, Op::Write) => {
+            let value = Bytes::from(value);
+            Frame::Write {
+                id,
+                uuid: *uuid,
+                value,
+            }
+            .encode(buf)
+        }
+        (Mode::Redis, Op::Read) => {
+            buf.extend_from_slice(b"*2\r\n$3\r\nGET\r\n");
+            put_bulk(buf, uuid);
+        }
+        (Mode::Redis, Op::Write) => {
+            buf.extend_from_slice(b"*3\r\n$3\r\nSET\r\n");
+            put_bulk(buf, uuid);
+            put_bulk(buf, value);
+        }
+    }
+}
+
+fn put_bulk(buf: &mut BytesMut, value: &[u8]) {
+    buf.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(b"\r\n");
+}
+
+// The first complete reply in `buf`, with the request id it answers
+// for protostore. Consumes it from `buf`.
+fn decode_reply(mode: Mode, buf: &mut BytesMut) -> io::Result<Option<(u32, Reply)>> {
+    match mode {
+        Mode::Protostore => decode_protostore(buf),
+        Mode::Redis => decode_resp(buf).map(|reply| reply.map(|reply| (0, reply))),
+    }
+}
+
+fn decode_protostore(buf: &mut BytesMut) -> io::Result<Option<(u32, Reply)>> {
+    Ok(Response::decode(buf)?.map(|response| {
+        let reply = match response.status {
+            Status::Ok => Reply::Value(response.body.len()),
+            _ => Reply::Error,
+        };
+        (response.id, reply)
+    }))
+}
+
+fn decode_resp(buf: &mut BytesMut) -> io::Result<Option<Reply>> {
+    let line_end = match buf.windows(2).position(|pair| pair == b"\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let reply = match buf[0] {
+        b'+' | b':' => Reply::Value(0),
+        b'-' => Reply::Error,
+        b'$' => {
+            let len = std::str::from_utf8(&buf[1..line_end])
+                .ok()
+                .and_then(|len| len.parse::<i64>().ok())
+                .ok_or_else(|| invalid_data("bad bulk string length"))?;
+            if len < 0 {
+                Reply::Missing
+            } else {
+                let len = len as usize;
+                if buf.len() < line_end + 2 + len + 2 {
+                    return Ok(None);
+                }
+                buf.advance(line_end + 2 + len + 2);
+                return Ok(Some(Reply::Value(len)));
+            }
+        }
+        _ => return Err(invalid_data("unexpected redis reply")),
+    };
+    buf.advance(line_end + 2);
+    Ok(Some(reply))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
@@ -0,0 +1,486 @@
+// cargo run --bin protostore-cli -- --addr=127.0.0.1:8080 get 5f1c0c2a6e0d4a8c9b1e2f3a4b5c6d7e
+// cargo run --bin protostore-cli -- --path=./db stat 5f1c0c2a6e0d4a8c9b1e2f3a4b5c6d7e
+//
+// With --path the dataset is read from disk as it was built, values
+// written since then are only visible through the server.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{ByteOrder, LittleEndian};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use zstd::block::Decompressor;
+
+use protostore::{hash_key, TableOfContents};
+use protostore_client::{Client, Config};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let uuid = Arg::with_name("uuid")
+        .required(true)
+        .help("Uuid as 32 hex digits, dashes allowed");
+    let key = Arg::with_name("key")
+        .long("key")
+        .help("Take the uuid argument as a byte-string key instead");
+
+    let matches = App::new("protostore-cli")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .default_value("127.0.0.1:8080")
+                .help("Address of the server"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .help("Read the dataset in this directory instead of asking a server"),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Prints the value of a uuid")
+                .arg(uuid.clone())
+                .arg(key.clone())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["auto", "hex", "text", "protobuf", "raw"])
+                        .default_value("auto")
+                        .help("How to print the value, auto picks text, protobuf or hex"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Writes the value of a uuid")
+                .arg(uuid.clone())
+                .arg(Arg::with_name("value").required(true))
+                .arg(
+                    Arg::with_name("hex")
+                        .long("hex")
+                        .help("The value is given as hex digits"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .help("Seconds the value lives for"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("del")
+                .about("Deletes a uuid")
+                .arg(uuid.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("exists")
+                .about("Exits with 0 if a uuid has a value, 1 if it doesn't")
+                .arg(uuid.clone())
+                .arg(key.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("Prints where the record of a uuid is stored")
+                .arg(uuid)
+                .arg(key),
+        )
+        .subcommand(SubCommand::with_name("info").about("Prints what is being served"))
+        .get_matches();
+
+    let (command, args) = matches.subcommand();
+    let args = args.unwrap();
+    let target = match matches.value_of("path") {
+        Some(path) => Target::Local(PathBuf::from(path)),
+        None => Target::Remote(matches.value_of("addr").unwrap().parse()?),
+    };
+
+    match (command, target) {
+        ("get", Target::Local(path)) => {
+            let dataset = Local::open(&path)?;
+            let (uuid, key) = parse_uuid(args)?;
+            match dataset.get(&uuid, key.as_ref().map(|k| &k[..]))? {
+                Some(value) => print_value(&value, args.value_of("format").unwrap())?,
+                None => not_found(),
+            }
+        }
+        ("get", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let value = match parse_uuid(args)? {
+                (_, Some(key)) => client.get_key(&key).await?,
+                (uuid, None) => client.get(uuid).await?,
+            };
+            match value {
+                Some(value) => print_value(&value, args.value_of("format").unwrap())?,
+                None => not_found(),
+            }
+        }
+        ("exists", Target::Local(path)) => {
+            let dataset = Local::open(&path)?;
+            let (uuid, key) = parse_uuid(args)?;
+            if dataset.get(&uuid, key.as_ref().map(|k| &k[..]))?.is_none() {
+                not_found();
+            }
+        }
+        ("exists", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let value = match parse_uuid(args)? {
+                (_, Some(key)) => client.get_key(&key).await?,
+                (uuid, None) => client.get(uuid).await?,
+            };
+            if value.is_none() {
+                not_found();
+            }
+        }
+        ("stat", Target::Local(path)) => {
+            let dataset = Local::open(&path)?;
+            let (uuid, _) = parse_uuid(args)?;
+            print_json(&dataset.stat(&uuid))?;
+        }
+        ("stat", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let (uuid, _) = parse_uuid(args)?;
+            let stat = client.admin(&format!("stat {}", hex(&uuid))).await?;
+            print_json(&serde_json::from_str(&stat)?)?;
+        }
+        ("info", Target::Local(path)) => {
+            print_json(&Local::open(&path)?.info())?;
+        }
+        ("info", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let info = client.admin("info").await?;
+            print_json(&serde_json::from_str(&info)?)?;
+        }
+        ("put", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let (uuid, _) = parse_uuid(args)?;
+            let value = args.value_of("value").unwrap();
+            let value = if args.is_present("hex") {
+                from_hex(value)?
+            } else {
+                value.as_bytes().to_vec()
+            };
+            match args.value_of("ttl") {
+                Some(ttl) => {
+                    let ttl = Duration::from_secs(ttl.parse()?);
+                    client.put_ttl(uuid, &value, ttl).await?
+                }
+                None => client.put(uuid, &value).await?,
+            }
+        }
+        ("del", Target::Remote(addr)) => {
+            let client = Client::connect(addr, Config::default()).await?;
+            let (uuid, _) = parse_uuid(args)?;
+            client.delete(uuid).await?;
+        }
+        (command, Target::Local(_)) => {
+            eprintln!("{} goes through the server, drop --path", command);
+            process::exit(2);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+enum Target {
+    Local(PathBuf),
+    Remote(SocketAddr),
+}
+
+// The uuid argument, and the key it was hashed from with --key.
+fn parse_uuid(args: &ArgMatches) -> io::Result<([u8; 16], Option<Vec<u8>>)> {
+    let arg = args.value_of("uuid").unwrap();
+    if args.is_present("key") {
+        return Ok((hash_key(arg.as_bytes()), Some(arg.as_bytes().to_vec())));
+    }
+    match Uuid::parse_str(arg) {
+        Ok(uuid) => Ok((*uuid.as_bytes(), None)),
+        Err(e) => Err(invalid_input(format!("bad uuid {:?}: {}", arg, e))),
+    }
+}
+
+fn not_found() {
+    eprintln!("not found");
+    process::exit(1);
+}
+
+/// A dataset read straight from its files.
+struct Local {
+    path: PathBuf,
+    toc: TableOfContents,
+    data: File,
+}
+
+impl Local {
+    fn open(path: &Path) -> io::Result<Local> {
+        Ok(Local {
+            path: path.to_path_buf(),
+            toc: TableOfContents::from_path(path)?,
+            data: File::open(path.join("protostore.data"))?,
+        })
+    }
+
+    // The value as the server would return it: checked against its
+    // checksum, without the key prefix and decompressed.
+    fn get(&self, uuid: &[u8; 16], key: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+        let record = match self.toc.lookup(uuid) {
+            Some(record) if !record.is_expired(unix_now()) => record,
+            _ => return Ok(None),
+        };
+        let mut value = vec![0; record.len as usize];
+        self.data.read_exact_at(&mut value, record.offset)?;
+
+        if let Some(expected) = record.checksum {
+            let actual = crc32c::crc32c(&value);
+            if actual != expected {
+                return Err(invalid_data(format!(
+                    "checksum mismatch at offset {}: expected {:x}, got {:x}",
+                    record.offset, expected, actual
+                )));
+            }
+        }
+
+        if record.is_keyed() {
+            if value.len() < 2 {
+                return Err(invalid_data("malformed key prefix".to_owned()));
+            }
+            let key_len = LittleEndian::read_u16(&value[..2]) as usize;
+            if value.len() < 2 + key_len {
+                return Err(invalid_data("malformed key prefix".to_owned()));
+            }
+            // A different key that hashes to the same uuid
+            if key.map_or(false, |key| key != &value[2..2 + key_len]) {
+                return Ok(None);
+            }
+            value.drain(..2 + key_len);
+        }
+
+        if record.is_compressed() {
+            value = self.decompress(&value)?;
+        }
+        Ok(Some(value))
+    }
+
+    fn decompress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        if value.len() < 4 {
+            return Err(invalid_data("compressed value is too short".to_owned()));
+        }
+        let raw_len = LittleEndian::read_u32(&value[..4]) as usize;
+        match self.toc.dictionary() {
+            Some(dict) => Decompressor::with_dict(dict.to_vec()).decompress(&value[4..], raw_len),
+            None => Err(invalid_data("no dictionary to decompress with".to_owned())),
+        }
+    }
+
+    fn stat(&self, uuid: &[u8; 16]) -> Value {
+        match self.toc.lookup(uuid) {
+            Some(record) => json!({
+                "found": true,
+                "path": self.path,
+                "offset": record.offset,
+                "len": record.len,
+                "flags": record.flags,
+                "checksum": record.checksum,
+                "version": record.version,
+                "expires_at": record.expires_at,
+                "expired": record.is_expired(unix_now()),
+            }),
+            None => json!({ "found": false, "path": self.path }),
+        }
+    }
+
+    fn info(&self) -> Value {
+        let now = unix_now();
+        let expired = (0..self.toc.len())
+            .filter(|&index| self.toc.record(index).is_expired(now))
+            .count();
+        json!({
+            "path": self.path,
+            "toc_entries": self.toc.len(),
+            "expired_entries": expired,
+            "max_len": self.toc.max_len(),
+            "data_end": self.toc.data_end(),
+            "dictionary_len": self.toc.dictionary().map_or(0, |dict| dict.len()),
+        })
+    }
+}
+
+fn print_json(value: &Value) -> io::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_value(value: &[u8], format: &str) -> io::Result<()> {
+    let mut out = String::new();
+    match format {
+        "raw" => return io::stdout().write_all(value),
+        "hex" => hexdump(value, &mut out),
+        "text" => out.push_str(&String::from_utf8_lossy(value)),
+        "protobuf" => {
+            if protobuf(value, 0, &mut out).is_none() {
+                return Err(invalid_data("value is not a protobuf message".to_owned()));
+            }
+        }
+        _ => {
+            if printable(value) {
+                out.push_str(str::from_utf8(value).unwrap());
+            } else if protobuf(value, 0, &mut out).is_none() {
+                out.clear();
+                hexdump(value, &mut out);
+            }
+        }
+    }
+    println!("{}", out.trim_end());
+    Ok(())
+}
+
+// Offset, 16 bytes in hex and the same bytes as ASCII per line.
+fn hexdump(value: &[u8], out: &mut String) {
+    for (line, chunk) in value.chunks(16).enumerate() {
+        write!(out, "{:08x}  ", line * 16).unwrap();
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => write!(out, "{:02x} ", byte).unwrap(),
+                None => out.push_str("   "),
+            }
+            if i == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        for &byte in chunk {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push_str("|\n");
+    }
+}
+
+// Messages nested deeper than this are shown as hex, so a value made of
+// nested length prefixes can't run the stack out.
+const MAX_DEPTH: usize = 32;
+
+// Decodes `buf` as protobuf fields without a schema, by field number.
+// None when it isn't a well-formed message.
+fn protobuf(buf: &[u8], depth: usize, out: &mut String) -> Option<()> {
+    let indent = "  ".repeat(depth);
+    let mut pos = 0;
+    while pos < buf.len() {
+        let tag = varint(buf, &mut pos)?;
+        let field = tag >> 3;
+        if field == 0 {
+            return None;
+        }
+        match tag & 7 {
+            0 => writeln!(out, "{}{}: {}", indent, field, varint(buf, &mut pos)?).unwrap(),
+            1 => {
+                let bytes = buf.get(pos..pos + 8)?;
+                pos += 8;
+                let value = LittleEndian::read_u64(bytes);
+                let double = f64::from_bits(value);
+                writeln!(out, "{}{}: {} (double {})", indent, field, value, double).unwrap();
+            }
+            2 => {
+                let len = varint(buf, &mut pos)? as usize;
+                let bytes = buf.get(pos..pos.checked_add(len)?)?;
+                pos += len;
+                let mut nested = String::new();
+                // Strings are tried first, short ones often parse as messages
+                if printable(bytes) {
+                    let text = str::from_utf8(bytes).unwrap();
+                    writeln!(out, "{}{}: {:?}", indent, field, text).unwrap();
+                } else if !bytes.is_empty()
+                    && depth + 1 < MAX_DEPTH
+                    && protobuf(bytes, depth + 1, &mut nested).is_some()
+                {
+                    writeln!(out, "{}{} {{\n{}{}}}", indent, field, nested, indent).unwrap();
+                } else {
+                    writeln!(out, "{}{}: 0x{}", indent, field, hex(bytes)).unwrap();
+                }
+            }
+            5 => {
+                let bytes = buf.get(pos..pos + 4)?;
+                pos += 4;
+                let value = LittleEndian::read_u32(bytes);
+                let float = f32::from_bits(value);
+                writeln!(out, "{}{}: {} (float {})", indent, field, value, float).unwrap();
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn printable(bytes: &[u8]) -> bool {
+    match str::from_utf8(bytes) {
+        Ok(text) => text
+            .chars()
+            .all(|c| !c.is_control() || c == '\n' || c == '\t'),
+        Err(_) => false,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    // Slicing by two bytes would split multi-byte characters
+    if !text.is_ascii() {
+        return Err(invalid_input(format!("not hex: {:?}", text)));
+    }
+    if text.len() % 2 != 0 {
+        return Err(invalid_input(format!(
+            "odd number of hex digits in {:?}",
+            text
+        )));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16)
+                .map_err(|e| invalid_input(format!("bad hex {:?}: {}", text, e)))
+        })
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time drift!")
+        .as_secs()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,54 @@
+// cargo run --bin protostore-compact -- --path=/mnt/data/ --output=/mnt/data.compacted/
+
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{App, Arg};
+
+use protostore::compact;
+
+fn main() {
+    let matches = App::new("protostore-compact")
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .required(true)
+                .help("Directory with the datafiles to compact"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .required(true)
+                .help("Directory to write the compacted datafiles"),
+        )
+        .get_matches();
+
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time drift!")
+        .as_secs();
+
+    println!("Compacting {:?} into {:?}", path, output);
+    let compaction = match compact(&path, &output, now) {
+        Ok(compaction) => compaction,
+        Err(e) => {
+            println!("Compaction failed: {}", e);
+            process::exit(1);
+        }
+    };
+
+    println!(
+        "Merged {} entries and {} writes, kept {}, dropped {} expired, data file is {} bytes",
+        compaction.entries,
+        compaction.writes,
+        compaction.kept,
+        compaction.dropped,
+        compaction.data_len
+    );
+}
@@ -0,0 +1,178 @@
+// cargo run --bin protostore-fsck -- --path=/mnt/data/ --verify-checksums
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::process;
+
+use clap::{App, Arg};
+
+use protostore::{Record, TableOfContents};
+
+// Only the first few problems of each kind are printed, the rest are
+// just counted.
+const MAX_REPORTED: usize = 10;
+
+struct Check {
+    name: &'static str,
+    problems: usize,
+}
+
+impl Check {
+    fn new(name: &'static str) -> Check {
+        Check { name, problems: 0 }
+    }
+
+    fn fail(&mut self, message: String) {
+        if self.problems < MAX_REPORTED {
+            println!("  {}: {}", self.name, message);
+        }
+        self.problems += 1;
+    }
+}
+
+fn main() {
+    let matches = App::new("protostore-fsck")
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .required(true)
+                .help("Directory with the datafiles to check"),
+        )
+        .arg(
+            Arg::with_name("verify_checksums")
+                .long("verify-checksums")
+                .help("Read every value and verify its checksum"),
+        )
+        .get_matches();
+
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    let verify_checksums = matches.is_present("verify_checksums");
+
+    let mut data_path = path.clone();
+    data_path.push("protostore.data");
+
+    println!("Checking dataset in {:?}", path);
+
+    let toc = match TableOfContents::from_path(&path) {
+        Ok(toc) => toc,
+        Err(e) => {
+            println!("Could not open table of contents: {}", e);
+            process::exit(1);
+        }
+    };
+    let data_file = match File::open(&data_path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Could not open {:?}: {}", data_path, e);
+            process::exit(1);
+        }
+    };
+    let data_len = data_file
+        .metadata()
+        .expect("Could not stat data file")
+        .len();
+
+    println!("{} entries, data file is {} bytes", toc.len(), data_len);
+
+    let mut sorted = Check::new("uuids sorted and unique");
+    for (i, pair) in toc.uuids().windows(2).enumerate() {
+        if pair[0] >= pair[1] {
+            sorted.fail(format!(
+                "entry {} {:?} is not before entry {} {:?}",
+                i,
+                pair[0],
+                i + 1,
+                pair[1]
+            ));
+        }
+    }
+
+    let mut bounds = Check::new("records inside data file");
+    for i in 0..toc.len() {
+        let record = toc.record(i);
+        match end(&record) {
+            Some(end) if end <= data_len => (),
+            Some(_) => bounds.fail(format!(
+                "entry {} at offset {} with len {} ends past {}",
+                i, record.offset, record.len, data_len
+            )),
+            None => bounds.fail(format!(
+                "entry {} at offset {} with len {} ends past the largest offset",
+                i, record.offset, record.len
+            )),
+        }
+    }
+
+    let mut overlaps = Check::new("records don't overlap");
+    let mut by_offset = (0..toc.len()).collect::<Vec<usize>>();
+    by_offset.sort_by_key(|i| toc.record(*i).offset);
+    for pair in by_offset.windows(2) {
+        let (prev, next) = (toc.record(pair[0]), toc.record(pair[1]));
+        // Records that overflow are already reported as out of bounds
+        if end(&prev).map_or(false, |end| end > next.offset) {
+            overlaps.fail(format!(
+                "entry {} at {}+{} overlaps entry {} at {}",
+                pair[0], prev.offset, prev.len, pair[1], next.offset
+            ));
+        }
+    }
+
+    let mut checksums = Check::new("checksums match");
+    if verify_checksums {
+        // Reading in offset order keeps the disk access sequential
+        let mut buf = vec![];
+        for i in by_offset.iter() {
+            let record = toc.record(*i);
+            let expected = match record.checksum {
+                Some(checksum) => checksum,
+                None => {
+                    checksums.fail("dataset has no checksums".to_owned());
+                    break;
+                }
+            };
+            if end(&record).map_or(true, |end| end > data_len) {
+                continue;
+            }
+
+            buf.resize(record.len as usize, 0);
+            if let Err(e) = data_file.read_exact_at(&mut buf, record.offset) {
+                checksums.fail(format!("entry {} could not be read: {}", i, e));
+                continue;
+            }
+            let actual = crc32c::crc32c(&buf);
+            if actual != expected {
+                checksums.fail(format!(
+                    "entry {} at offset {}: expected {:x}, got {:x}",
+                    i, record.offset, expected, actual
+                ));
+            }
+        }
+    }
+
+    let mut checks = vec![sorted, bounds, overlaps];
+    if verify_checksums {
+        checks.push(checksums);
+    }
+
+    println!("Summary:");
+    let mut total_problems = 0;
+    for check in checks.iter() {
+        let status = if check.problems == 0 { "ok" } else { "FAILED" };
+        println!(
+            "  {:<28} {} ({} problems)",
+            check.name, status, check.problems
+        );
+        total_problems += check.problems;
+    }
+
+    if total_problems > 0 {
+        process::exit(1);
+    }
+}
+
+// Where the record ends, None if a corrupt offset puts it past u64.
+fn end(record: &Record) -> Option<u64> {
+    record.offset.checked_add(record.len as u64)
+}
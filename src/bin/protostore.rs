@@ -1,129 +1,552 @@
 use tokio::net::TcpListener;
+use tokio_net::driver::Handle;
 
 use tokio::runtime::current_thread;
+use tokio::timer::delay;
 
 use futures::future;
 
 use std::cmp;
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use libc;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 
-use hwloc::{CpuSet, ObjectType, Topology, CPUBIND_THREAD};
+use hwloc::{ObjectType, Topology, CPUBIND_THREAD};
 
+use clap::{App, Arg};
 use env_logger;
+use rayon::ThreadPoolBuilder;
 
-use protostore::{ProtostoreServer, TableOfContents};
+use protostore::{
+    allowed_cpus, cpu_quota, health, plan_layout, reuseport_listeners, steer_by_cpu, thread_counts,
+    Admin, Core, DataIo, Datasets, HttpServer, ProtostoreServer, ReadBackend, ServerConfig,
+    Session, ValueCache,
+};
+
+// Pause after a failed accept, doubled while they keep failing
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Set from the SIGHUP handler, picked up by the reload thread along
+// with reloads requested by admin frames
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Set from the SIGTERM handler
+static DRAIN_REQUESTED: AtomicBool = AtomicBool::new(false);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let cores = hwloc_cores();
-    let processing_units = hwloc_processing_units();
-    let mut pu_index = 1; // 0 is reserved for main thread
+    let matches = App::new("protostore")
+        .arg(
+            Arg::with_name("read_backend")
+                .long("read-backend")
+                .takes_value(true)
+                .possible_values(&["direct", "buffered", "mmap"])
+                .default_value("direct")
+                .help("How to read protostore.data, direct needs O_DIRECT support"),
+        )
+        .arg(
+            Arg::with_name("enable_writes")
+                .long("enable-writes")
+                .help("Open the dataset for writing and accept write and append requests"),
+        )
+        .arg(
+            Arg::with_name("cache_bytes")
+                .long("cache-bytes")
+                .takes_value(true)
+                .default_value("268435456")
+                .help("Memory for caching values read from disk, 0 disables the cache"),
+        )
+        .arg(
+            Arg::with_name("blocking_threads")
+                .long("blocking-threads")
+                .takes_value(true)
+                .help("Threads for the reads and writes of the buffered and mmap backends"),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .env("PROTOSTORE_ADMIN_TOKEN")
+                .takes_value(true)
+                .help("Token admin frames have to carry to reload the dataset or toggle read-only"),
+        )
+        .arg(
+            Arg::with_name("thread_per_core")
+                .long("thread-per-core")
+                .help("Run one thread per core that serves its sockets and does its own AIO"),
+        )
+        .arg(
+            Arg::with_name("reuseport")
+                .long("reuseport")
+                .help("Every TCP thread accepts on its own SO_REUSEPORT listener"),
+        )
+        .arg(
+            Arg::with_name("steer_by_cpu")
+                .long("steer-by-cpu")
+                .requires("reuseport")
+                .help("Steer connections to the listener of the CPU that received them"),
+        )
+        .arg(
+            Arg::with_name("nic")
+                .long("nic")
+                .takes_value(true)
+                .help("Network interface clients connect through, TCP threads go on its NUMA node"),
+        )
+        .arg(
+            Arg::with_name("http_addr")
+                .long("http-addr")
+                .takes_value(true)
+                .default_value("0.0.0.0:8081")
+                .help("Address to serve Prometheus metrics and health probes on"),
+        )
+        .arg(
+            Arg::with_name("drain_secs")
+                .long("drain-secs")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds to keep serving after SIGTERM while readiness reports draining"),
+        )
+        .arg(
+            Arg::with_name("trace_sample_rate")
+                .long("trace-sample-rate")
+                .takes_value(true)
+                .default_value("0")
+                .help("Fraction of requests traced with spans, from 0 to 1"),
+        )
+        .arg(
+            Arg::with_name("slow_request_ms")
+                .long("slow-request-ms")
+                .takes_value(true)
+                .help("Log the details of requests that take this many milliseconds or more"),
+        )
+        .get_matches();
+    let read_backend: ReadBackend = matches.value_of("read_backend").unwrap().parse()?;
+    let enable_writes = matches.is_present("enable_writes");
+    let cache_bytes: usize = matches.value_of("cache_bytes").unwrap().parse()?;
+    let thread_per_core = matches.is_present("thread_per_core");
+    let reuseport = matches.is_present("reuseport");
+    let drain_secs: u64 = matches.value_of("drain_secs").unwrap().parse()?;
+    let slow_request = match matches.value_of("slow_request_ms") {
+        Some(ms) => Some(Duration::from_millis(ms.parse()?)),
+        None => None,
+    };
+    let config = ServerConfig {
+        short_circuit_reads: false,
+        trace_sample_rate: matches.value_of("trace_sample_rate").unwrap().parse()?,
+        slow_request,
+    };
+
+    let data_dir = Path::new("./db");
+
+    // Only the processing units the affinity mask and cgroup cpuset
+    // allow, with threads sized to those or to the cgroup CPU quota.
+    let allowed = allowed_cpus();
+    let cores = hwloc_cores(&allowed);
+    let num_pus = cores.iter().map(|core| core.pus.len()).sum::<usize>();
     info!(
-        "Found total of {} cores, total of {} processing units",
+        "Found total of {} cores, total of {} processing units allowed",
         cores.len(),
-        processing_units.len()
+        num_pus
+    );
+    let available = if thread_per_core {
+        cores.len()
+    } else {
+        num_pus
+    };
+    let cpus = match cpu_quota() {
+        Some(quota) => {
+            info!("CPU quota of {:.2} CPUs", quota);
+            cmp::min(available, quota.ceil() as usize)
+        }
+        None => available,
+    };
+    // With a thread per core each TCP thread has its own AIO context
+    let (num_aio_threads, num_tcp_threads) = thread_counts(cpus, thread_per_core);
+
+    let storage_node = storage_numa_node(data_dir);
+    let nic_node = matches.value_of("nic").and_then(nic_numa_node);
+    info!(
+        "Storage on NUMA node {:?}, NIC on {:?}",
+        storage_node, nic_node
+    );
+    let layout = plan_layout(
+        &cores,
+        storage_node,
+        nic_node,
+        num_aio_threads,
+        num_tcp_threads,
     );
+    info!("Thread layout: {}", layout);
 
-    let short_circuit_reads = false;
+    // The thread that accepts new connections and runs other
+    // low-intensity tasks. It sits next to the storage device so the
+    // table of contents it loads is allocated on that node.
+    bind_thread_to_processing_unit(unsafe { libc::pthread_self() }, layout.main.pu);
+
+    // Up before the table of contents loads, readiness tells when
+    // it's done and every AIO session has started
+    let http_addr: SocketAddr = matches.value_of("http_addr").unwrap().parse()?;
+    let http = HttpServer::spawn(http_addr)?;
+    health().expect_aio_sessions(if num_aio_threads > 0 {
+        num_aio_threads
+    } else {
+        num_tcp_threads
+    });
+    spawn_drainer(Duration::from_secs(drain_secs));
 
     //
     // Read Table of Contents
     //
-    let data_dir = Path::new("./db");
-    let toc =
-        Arc::new(TableOfContents::from_path(data_dir).expect("Could not open table of contents"));
-    let max_value_len = toc.max_len();
-    debug!("TOC len {:?}", max_value_len);
+    let datasets = Arc::new(
+        Datasets::open(data_dir, read_backend, enable_writes).expect("Could not open dataset"),
+    );
+    debug!("TOC len {:?}", datasets.current().toc.max_len());
+    health().toc_loaded();
+
+    let admin_token = matches.value_of("admin_token").map(str::to_owned);
+    if admin_token.is_none() {
+        info!("No admin token, admin frames can't reload or toggle read-only");
+    }
+    let admin = Arc::new(Admin::new(
+        PathBuf::from(data_dir),
+        layout.clone(),
+        admin_token,
+    ));
+
+    // Point ./db at a new dataset and send SIGHUP to switch to it
+    spawn_reloader(
+        datasets.clone(),
+        admin.clone(),
+        PathBuf::from(data_dir),
+        layout.main.pu,
+    );
+
+    // Shared by all connections, so writes invalidate it everywhere
+    let cache = Arc::new(ValueCache::new(cache_bytes));
+
+    http.serve(datasets.clone(), cache.clone());
+
+    //
+    // Create threads for AIO
+    //
+
+    let max_queue_depth = 512;
+    let mut aio_sessions = vec![];
+
+    for i in 0..num_aio_threads {
+        let pu = layout.aio[i].pu;
+        info!("aio_loop id:{} processing_unit:{}", i, pu);
+
+        let session = Session::new(max_queue_depth).expect("Could not start AIO session");
+        bind_thread_to_processing_unit(session.thread_id(), pu);
+        aio_sessions.push(session);
+    }
+
+    // Blocking reads and writes of the buffered and mmap backends. They
+    // mostly wait on the disk, so a few per TCP thread keep it busy.
+    let num_blocking_threads = match matches.value_of("blocking_threads") {
+        Some(threads) => threads.parse()?,
+        None => cmp::max(4, 2 * num_tcp_threads),
+    };
+    info!("{} blocking IO threads", num_blocking_threads);
+    let blocking_pool = Arc::new(
+        ThreadPoolBuilder::new()
+            .num_threads(num_blocking_threads)
+            .thread_name(|i| format!("blocking-io-{}", i))
+            .build()?,
+    );
 
     //
     // Create threads for handling client comms
     //
 
-    let num_tcp_threads = 8;
     let mut tcp_threads = vec![];
 
+    let addr: SocketAddr = "0.0.0.0:8080".parse()?;
+    let mut listeners: Vec<Option<std::net::TcpListener>> = if reuseport {
+        let listeners = reuseport_listeners(&addr, num_tcp_threads)?;
+        if matches.is_present("steer_by_cpu") {
+            // The filter sees OS CPU ids, threads are pinned by hwloc index
+            let cpus = layout
+                .tcp
+                .iter()
+                .map(|slot| os_cpu(slot.pu))
+                .collect::<Vec<_>>();
+            steer_by_cpu(&listeners[0], &cpus)?;
+        }
+        listeners.into_iter().map(Some).collect()
+    } else {
+        (0..num_tcp_threads).map(|_| None).collect()
+    };
+
     let (remote_tx, remote_rx) = mpsc::channel();
     for i in 0..num_tcp_threads {
-        let pu = cmp::min(pu_index, processing_units.len() - 1);
-        pu_index += 1;
+        let pu = layout.tcp[i].pu;
         info!("tcp_loop id:{} processing_unit:{}", i, pu);
 
         let remote_tx = remote_tx.clone();
+        let listener = listeners[i].take();
+        // None when the thread runs its own AIO context
+        let shared_aio = aio_sessions
+            .get(i % cmp::max(1, num_aio_threads))
+            .map(|session| session.handle());
+        let datasets = datasets.clone();
+        let cache = cache.clone();
+        let admin = admin.clone();
+        let blocking_pool = blocking_pool.clone();
         let tid = thread::spawn(move || {
             debug!("started thread with id {:?}", unsafe {
                 libc::pthread_self()
             });
             bind_thread_to_processing_unit(unsafe { libc::pthread_self() }, pu);
             let mut rt = current_thread::Runtime::new().unwrap();
-            remote_tx.send(rt.handle()).unwrap();
+            let aio = match shared_aio {
+                Some(aio) => aio,
+                None => {
+                    let (aio, aio_loop) =
+                        Session::local(max_queue_depth).expect("Could not start AIO session");
+                    rt.spawn(aio_loop);
+                    aio
+                }
+            };
+            let io = DataIo::new(aio, blocking_pool);
             let handle = rt.handle();
+            match listener {
+                Some(listener) => {
+                    let listener = TcpListener::from_std(listener, &Handle::default())
+                        .expect("Could not register listener");
+                    let _ = handle.spawn(accept_loop(
+                        listener,
+                        handle.clone(),
+                        datasets,
+                        cache,
+                        io,
+                        admin,
+                        config,
+                    ));
+                }
+                None => remote_tx.send((handle.clone(), io)).unwrap(),
+            }
             let _ = handle.spawn(future::pending());
             let _ = rt.run();
             debug!("thread ending?");
         });
         tcp_threads.push(tid);
     }
+    drop(remote_tx);
 
-    let tcp_handles: Vec<current_thread::Handle> =
+    if reuseport {
+        info!("Every TCP thread is accepting connections on {}", addr);
+        future::pending::<()>().await;
+    }
+
+    let tcp_handles: Vec<(current_thread::Handle, DataIo)> =
         remote_rx.into_iter().take(num_tcp_threads).collect();
     let tcp_handles_index = AtomicUsize::new(0);
 
     info!("listening");
-    let addr = "0.0.0.0:8080".parse()?;
     let mut listener = TcpListener::bind(&addr).unwrap();
 
-    // Bind the thread that accepts new connections to a dedicated
-    // processing unit where we also run other low-intensity tasks
-    bind_thread_to_processing_unit(unsafe { libc::pthread_self() }, 0);
-
     info!("Now accepting connections on {}", addr);
     loop {
         let (socket, _) = listener.accept().await?;
         info!("Got new connection from {}", addr);
 
         let tcp_idx = tcp_handles_index.fetch_add(1, Ordering::SeqCst) % num_tcp_threads;
-        let tcp_handle = &tcp_handles[tcp_idx];
+        let (tcp_handle, io) = &tcp_handles[tcp_idx];
 
-        let toc = toc.clone();
+        let datasets = datasets.clone();
+        let cache = cache.clone();
+        let io = io.clone();
+        let admin = admin.clone();
         let _r = tcp_handle.spawn(async move {
-            let mut server = ProtostoreServer::new(socket, toc, max_value_len, short_circuit_reads);
+            let mut server = ProtostoreServer::new(socket, datasets, cache, io, admin, config);
+            let _ = server.handle_client().await;
+        });
+    }
+}
+
+// Accepts the connections of one TCP thread's own listener
+async fn accept_loop(
+    mut listener: TcpListener,
+    handle: current_thread::Handle,
+    datasets: Arc<Datasets>,
+    cache: Arc<ValueCache>,
+    io: DataIo,
+    admin: Arc<Admin>,
+    config: ServerConfig,
+) {
+    // Errors like running out of file descriptors last until some
+    // connections close, retrying right away would spin
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("failed to accept connection; err = {:?}", e);
+                delay(Instant::now() + backoff).await;
+                backoff = cmp::min(2 * backoff, MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        backoff = MIN_ACCEPT_BACKOFF;
+        info!("Got new connection from {}", peer);
+
+        let datasets = datasets.clone();
+        let cache = cache.clone();
+        let io = io.clone();
+        let admin = admin.clone();
+        let _r = handle.spawn(async move {
+            let mut server = ProtostoreServer::new(socket, datasets, cache, io, admin, config);
             let _ = server.handle_client().await;
         });
     }
 }
 
-fn hwloc_processing_units() -> Vec<CpuSet> {
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn spawn_reloader(datasets: Arc<Datasets>, admin: Arc<Admin>, data_dir: PathBuf, pu: usize) {
+    unsafe {
+        libc::signal(libc::SIGHUP, request_reload as libc::sighandler_t);
+    }
+
+    // Shares the main thread's processing unit, so reloaded tables of
+    // contents land on the same node
+    thread::spawn(move || {
+        bind_thread_to_processing_unit(unsafe { libc::pthread_self() }, pu);
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            // Both are taken so neither is left over for the next round
+            let signaled = RELOAD_REQUESTED.swap(false, Ordering::SeqCst);
+            if admin.take_reload_request() || signaled {
+                match datasets.reload(&data_dir) {
+                    Ok(generation) => info!("Now serving generation {}", generation),
+                    Err(e) => error!("Could not reload dataset from {:?}: {}", data_dir, e),
+                }
+            }
+        }
+    });
+}
+
+extern "C" fn request_drain(_signal: libc::c_int) {
+    DRAIN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// On SIGTERM readiness drops, and connections keep being served for
+// `grace` so load balancers can take the server out of rotation first.
+fn spawn_drainer(grace: Duration) {
+    unsafe {
+        libc::signal(libc::SIGTERM, request_drain as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !DRAIN_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!("Draining for {:?} before exiting", grace);
+        health().drain();
+        thread::sleep(grace);
+        process::exit(0);
+    });
+}
+
+// Physical cores with the NUMA node they are on and the indexes of
+// their processing units, leaving out the ones not in `allowed`.
+fn hwloc_cores(allowed: &[usize]) -> Vec<Core> {
     if cfg!(target_os = "macos") {
-        return (0..8).into_iter().map(|x| CpuSet::from(x)).collect();
+        return (0..8)
+            .map(|pu| Core {
+                node: 0,
+                pus: vec![pu],
+            })
+            .collect();
     }
     let topo = Topology::new();
-    let cores = topo.objects_with_type(&ObjectType::PU).unwrap();
+    let pus = topo.objects_with_type(&ObjectType::PU).unwrap();
+    let nodes = topo
+        .objects_with_type(&ObjectType::NUMANode)
+        .unwrap_or_else(|_| vec![]);
+    let cores = topo.objects_with_type(&ObjectType::Core).unwrap();
     cores
         .iter()
-        .map(|c| c.cpuset().unwrap())
-        .collect::<Vec<CpuSet>>()
+        .filter_map(|core| {
+            let cpuset = core.cpuset().unwrap();
+            let core_pus = pus
+                .iter()
+                .enumerate()
+                .filter(|(_, pu)| cpuset.is_set(pu.os_index()))
+                .filter(|(_, pu)| allowed.is_empty() || allowed.contains(&(pu.os_index() as usize)))
+                .map(|(index, _)| index)
+                .collect::<Vec<usize>>();
+            let first = pus[*core_pus.first()?].os_index();
+            let node = nodes
+                .iter()
+                .find(|node| node.cpuset().map_or(false, |set| set.is_set(first)))
+                .map_or(0, |node| node.os_index() as usize);
+            Some(Core {
+                node,
+                pus: core_pus,
+            })
+        })
+        .collect()
 }
 
-fn hwloc_cores() -> Vec<CpuSet> {
+// NUMA node of the block device holding `path`, from sysfs.
+fn storage_numa_node(path: &Path) -> Option<usize> {
+    let dev = fs::metadata(path).ok()?.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let block = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+    // Partitions don't have a device link, the disk they are on does
+    read_numa_node(&block.join("device/numa_node"))
+        .or_else(|| read_numa_node(&block.join("../device/numa_node")))
+}
+
+fn nic_numa_node(nic: &str) -> Option<usize> {
+    read_numa_node(
+        &Path::new("/sys/class/net")
+            .join(nic)
+            .join("device/numa_node"),
+    )
+}
+
+// Devices not attached to a specific node report -1
+fn read_numa_node(path: &Path) -> Option<usize> {
+    let node = fs::read_to_string(path).ok()?.trim().parse::<i64>().ok()?;
+    if node < 0 {
+        None
+    } else {
+        Some(node as usize)
+    }
+}
+
+// OS id of the processing unit at hwloc index `idx`
+fn os_cpu(idx: usize) -> usize {
     if cfg!(target_os = "macos") {
-        return (0..4).into_iter().map(|x| CpuSet::from(x)).collect();
+        return idx;
     }
+
     let topo = Topology::new();
-    let cores = topo.objects_with_type(&ObjectType::Core).unwrap();
-    cores
-        .iter()
-        .map(|c| c.cpuset().unwrap())
-        .collect::<Vec<CpuSet>>()
+    match topo.objects_with_type(&ObjectType::PU).unwrap().get(idx) {
+        Some(pu) => pu.os_index() as usize,
+        None => idx,
+    }
 }
 
+// Pinning is best effort, it may not be allowed in containers
 fn bind_thread_to_processing_unit(thread: libc::pthread_t, idx: usize) {
     if cfg!(target_os = "macos") {
         return;
@@ -132,8 +555,12 @@ fn bind_thread_to_processing_unit(thread: libc::pthread_t, idx: usize) {
     let mut topo = Topology::new();
     let bind_to = match topo.objects_with_type(&ObjectType::PU).unwrap().get(idx) {
         Some(val) => val.cpuset().unwrap(),
-        None => panic!("No processing unit found for idx {}", idx),
+        None => {
+            warn!("No processing unit found for idx {}, not pinning", idx);
+            return;
+        }
     };
-    topo.set_cpubind_for_thread(thread, bind_to, CPUBIND_THREAD)
-        .expect("Could not set cpubind for thread");
+    if let Err(e) = topo.set_cpubind_for_thread(thread, bind_to, CPUBIND_THREAD) {
+        warn!("Could not pin thread to processing unit {}: {:?}", idx, e);
+    }
 }
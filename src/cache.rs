@@ -0,0 +1,309 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+const SHARDS: usize = 16;
+
+// Accesses counted per entry, past this they don't make it any hotter
+const MAX_FREQ: u8 = 3;
+
+/// Values read from disk, kept in memory for the reads that follow.
+/// Each shard is an S3-FIFO: new values go through a small queue and
+/// only the ones read again while there move to the main queue, so a
+/// scan can't push the hot values out.
+///
+/// Entries remember the generation and offset they were read from and
+/// only match a record that is still stored there.
+#[derive(Debug)]
+pub struct ValueCache {
+    capacity: usize,
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl ValueCache {
+    /// A cache of up to `capacity` bytes of values, 0 disables it.
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(Shard::new(capacity / SHARDS)))
+                .collect(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether a value of `len` bytes would be kept. Reads of the ones
+    /// that wouldn't can skip the cache altogether.
+    pub fn admits(&self, len: usize) -> bool {
+        // Values that would take most of a shard aren't worth it
+        self.capacity > 0 && len <= self.capacity / SHARDS / 10
+    }
+
+    pub fn get(&self, uuid: &[u8; 16], generation: u64, offset: u64) -> Option<Bytes> {
+        let value = self
+            .shard(uuid)
+            .lock()
+            .unwrap()
+            .get(uuid, generation, offset);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, uuid: &[u8; 16], generation: u64, offset: u64, value: Bytes) {
+        if !self.admits(value.len()) {
+            return;
+        }
+        self.shard(uuid)
+            .lock()
+            .unwrap()
+            .insert(uuid, generation, offset, value);
+    }
+
+    pub fn remove(&self, uuid: &[u8; 16]) {
+        self.shard(uuid).lock().unwrap().remove(uuid);
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of values currently cached.
+    pub fn size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard.small_bytes + shard.main_bytes
+            })
+            .sum()
+    }
+
+    fn shard(&self, uuid: &[u8; 16]) -> &Mutex<Shard> {
+        // uuids are random or hashed, any byte spreads them evenly
+        &self.shards[uuid[15] as usize % SHARDS]
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    generation: u64,
+    offset: u64,
+    value: Bytes,
+    freq: u8,
+    in_main: bool,
+    // Tells this entry apart from queue slots left by a removed one
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Shard {
+    capacity: usize,
+    entries: HashMap<[u8; 16], Entry>,
+    small: VecDeque<([u8; 16], u64)>,
+    main: VecDeque<([u8; 16], u64)>,
+    small_bytes: usize,
+    main_bytes: usize,
+    // Recently evicted from the small queue, go straight to main if
+    // they come back
+    ghost: VecDeque<[u8; 16]>,
+    ghost_set: HashSet<[u8; 16]>,
+    next_seq: u64,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            capacity,
+            entries: HashMap::new(),
+            small: VecDeque::new(),
+            main: VecDeque::new(),
+            small_bytes: 0,
+            main_bytes: 0,
+            ghost: VecDeque::new(),
+            ghost_set: HashSet::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn get(&mut self, uuid: &[u8; 16], generation: u64, offset: u64) -> Option<Bytes> {
+        match self.entries.get_mut(uuid) {
+            Some(entry) if entry.generation == generation && entry.offset == offset => {
+                entry.freq = (entry.freq + 1).min(MAX_FREQ);
+                Some(entry.value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, uuid: &[u8; 16], generation: u64, offset: u64, value: Bytes) {
+        self.remove(uuid);
+
+        let in_main = self.ghost_set.remove(uuid);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if in_main {
+            self.main.push_back((*uuid, seq));
+            self.main_bytes += value.len();
+        } else {
+            self.small.push_back((*uuid, seq));
+            self.small_bytes += value.len();
+        }
+        self.entries.insert(
+            *uuid,
+            Entry {
+                generation,
+                offset,
+                value,
+                freq: 0,
+                in_main,
+                seq,
+            },
+        );
+
+        while self.small_bytes + self.main_bytes > self.capacity {
+            if self.small_bytes > self.capacity / 10 || self.main.is_empty() {
+                self.evict_small();
+            } else {
+                self.evict_main();
+            }
+        }
+    }
+
+    fn remove(&mut self, uuid: &[u8; 16]) {
+        if let Some(entry) = self.entries.remove(uuid) {
+            if entry.in_main {
+                self.main_bytes -= entry.value.len();
+            } else {
+                self.small_bytes -= entry.value.len();
+            }
+        }
+    }
+
+    fn evict_small(&mut self) {
+        while let Some((uuid, seq)) = self.small.pop_front() {
+            let entry = match self.entries.get_mut(&uuid) {
+                Some(entry) if entry.seq == seq => entry,
+                _ => continue,
+            };
+
+            let len = entry.value.len();
+            self.small_bytes -= len;
+            if entry.freq > 0 {
+                entry.freq = 0;
+                entry.in_main = true;
+                self.main_bytes += len;
+                self.main.push_back((uuid, seq));
+                // Making room in main is up to the caller
+                return;
+            }
+
+            self.entries.remove(&uuid);
+            if self.ghost_set.insert(uuid) {
+                self.ghost.push_back(uuid);
+            }
+            while self.ghost.len() > self.entries.len() {
+                let old = self.ghost.pop_front().unwrap();
+                self.ghost_set.remove(&old);
+            }
+            return;
+        }
+    }
+
+    fn evict_main(&mut self) {
+        while let Some((uuid, seq)) = self.main.pop_front() {
+            let entry = match self.entries.get_mut(&uuid) {
+                Some(entry) if entry.seq == seq => entry,
+                _ => continue,
+            };
+
+            if entry.freq > 0 {
+                entry.freq -= 1;
+                self.main.push_back((uuid, seq));
+                continue;
+            }
+
+            self.main_bytes -= entry.value.len();
+            self.entries.remove(&uuid);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::ValueCache;
+
+    fn uuid(n: u8) -> [u8; 16] {
+        let mut uuid = [0; 16];
+        uuid[0] = n;
+        uuid
+    }
+
+    #[test]
+    fn get_matches_offset_and_generation() {
+        let cache = ValueCache::new(16 * 1024);
+        cache.insert(&uuid(1), 1, 512, Bytes::from(&b"abcd"[..]));
+
+        assert_eq!(Some(Bytes::from(&b"abcd"[..])), cache.get(&uuid(1), 1, 512));
+        assert_eq!(None, cache.get(&uuid(1), 1, 1024));
+        assert_eq!(None, cache.get(&uuid(1), 2, 512));
+        assert_eq!(1, cache.hits());
+        assert_eq!(2, cache.misses());
+
+        cache.remove(&uuid(1));
+        assert_eq!(None, cache.get(&uuid(1), 1, 512));
+        assert_eq!(0, cache.size());
+    }
+
+    #[test]
+    fn admits() {
+        let cache = ValueCache::new(16 * 1024);
+        assert!(cache.admits(100));
+        assert!(!cache.admits(200));
+
+        let disabled = ValueCache::new(0);
+        assert!(!disabled.admits(0));
+        disabled.insert(&uuid(1), 1, 0, Bytes::new());
+        assert_eq!(None, disabled.get(&uuid(1), 1, 0));
+    }
+
+    #[test]
+    fn bounded() {
+        // All uuids land in the same shard, 1KiB of it
+        let cache = ValueCache::new(16 * 1024);
+        for n in 0..100 {
+            cache.insert(&uuid(n), 1, 0, Bytes::from(vec![n; 100]));
+        }
+        assert!(cache.size() <= 1024);
+        assert_eq!(Some(Bytes::from(vec![99; 100])), cache.get(&uuid(99), 1, 0));
+    }
+
+    #[test]
+    fn scan_resistant() {
+        let cache = ValueCache::new(16 * 1024);
+        cache.insert(&uuid(0), 1, 0, Bytes::from(vec![0; 100]));
+        cache.get(&uuid(0), 1, 0);
+
+        // Values read once don't push out one that was read again
+        for n in 1..100 {
+            cache.insert(&uuid(n), 1, 0, Bytes::from(vec![n; 100]));
+        }
+        assert!(cache.get(&uuid(0), 1, 0).is_some());
+    }
+}
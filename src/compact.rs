@@ -0,0 +1,214 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use bytes::{ByteOrder, LittleEndian};
+
+use crate::overlay::Overlay;
+use crate::toc::TableOfContents;
+
+/// What a compaction read and what it kept.
+#[derive(Debug, PartialEq)]
+pub struct Compaction {
+    pub entries: usize,
+    pub writes: usize,
+    pub kept: usize,
+    pub dropped: usize,
+    pub data_len: u64,
+}
+
+/// Writes the dataset at `path` to `output` with the written records
+/// folded into the table of contents and the ones expired at `now`
+/// dropped.
+pub fn compact(path: &Path, output: &Path, now: u64) -> Result<Compaction, io::Error> {
+    // The output data file is truncated before the values are copied
+    // out of the input one
+    if same_dir(path, output) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "output has to be a different directory than the dataset",
+        ));
+    }
+
+    let toc = TableOfContents::from_path(path)?;
+    let data_file = File::open(path.join("protostore.data"))?;
+    let data_len = data_file.metadata()?.len();
+    // Only reads the log, if the dataset was ever written to
+    let overlay = Overlay::open(path, data_len, false)?;
+
+    // Written records replace the ones the dataset was built with, and
+    // writes to new uuids are merged in.
+    let mut records = (0..toc.len())
+        .map(|index| (toc.uuids()[index], toc.record(index)))
+        .filter(|(uuid, _)| overlay.lookup(uuid).is_none())
+        .chain(overlay.records())
+        .collect::<Vec<_>>();
+    records.sort_by_key(|(uuid, _)| *uuid);
+
+    let mut toc_uuids_buf: Vec<u8> = Vec::with_capacity(16 * records.len());
+    let mut toc_offsets_buf: Vec<u8> = Vec::with_capacity(8 * records.len());
+    let mut toc_lens_buf: Vec<u8> = Vec::with_capacity(4 * records.len());
+    let mut toc_checksums_buf: Vec<u8> = Vec::with_capacity(4 * records.len());
+    let mut toc_flags_buf: Vec<u8> = Vec::with_capacity(records.len());
+    let mut toc_expiry_buf: Vec<u8> = Vec::with_capacity(8 * records.len());
+    let mut toc_versions_buf: Vec<u8> = Vec::with_capacity(8 * records.len());
+
+    fs::create_dir_all(output)?;
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    let mut data_out = BufWriter::new(opts.open(output.join("protostore.data"))?);
+
+    let mut offset: u64 = 0;
+    let mut dropped = 0;
+    let mut has_checksums = true;
+    let mut buf = vec![];
+    for (uuid, record) in records.iter() {
+        if record.is_expired(now) {
+            dropped += 1;
+            continue;
+        }
+
+        buf.resize(record.len as usize, 0);
+        data_file.read_exact_at(&mut buf, record.offset)?;
+        data_out.write_all(&buf)?;
+
+        let mut encoded_offset = [0; 8];
+        let mut encoded_len = [0; 4];
+        let mut encoded_checksum = [0; 4];
+        let mut encoded_expiry = [0; 8];
+        let mut encoded_version = [0; 8];
+        LittleEndian::write_u64(&mut encoded_offset, offset);
+        LittleEndian::write_u32(&mut encoded_len, record.len);
+        LittleEndian::write_u32(&mut encoded_checksum, record.checksum.unwrap_or(0));
+        LittleEndian::write_u64(&mut encoded_expiry, record.expires_at);
+        LittleEndian::write_u64(&mut encoded_version, record.version);
+        has_checksums &= record.checksum.is_some();
+
+        toc_uuids_buf.extend_from_slice(uuid);
+        toc_offsets_buf.extend_from_slice(&encoded_offset);
+        toc_lens_buf.extend_from_slice(&encoded_len);
+        toc_checksums_buf.extend_from_slice(&encoded_checksum);
+        toc_flags_buf.push(record.flags);
+        toc_expiry_buf.extend_from_slice(&encoded_expiry);
+        toc_versions_buf.extend_from_slice(&encoded_version);
+
+        offset += record.len as u64;
+    }
+    data_out.into_inner()?.sync_all()?;
+
+    write_file(&opts, output, "protostore.toc.uuids", &toc_uuids_buf)?;
+    write_file(&opts, output, "protostore.toc.offsets", &toc_offsets_buf)?;
+    write_file(&opts, output, "protostore.toc.lengths", &toc_lens_buf)?;
+    if has_checksums {
+        write_file(
+            &opts,
+            output,
+            "protostore.toc.checksums",
+            &toc_checksums_buf,
+        )?;
+    }
+    if toc_flags_buf.iter().any(|flags| *flags != 0) {
+        write_file(&opts, output, "protostore.toc.flags", &toc_flags_buf)?;
+    }
+    if toc_expiry_buf.iter().any(|b| *b != 0) {
+        write_file(&opts, output, "protostore.toc.expiry", &toc_expiry_buf)?;
+    }
+    // Without it every record would be back at version 1, and a client
+    // holding a version from before the compaction could overwrite a
+    // newer value
+    if toc_versions_buf
+        .chunks_exact(8)
+        .any(|version| LittleEndian::read_u64(version) != 1)
+    {
+        write_file(&opts, output, "protostore.toc.versions", &toc_versions_buf)?;
+    }
+    if let Some(dict) = toc.dictionary() {
+        write_file(&opts, output, "protostore.dict", dict)?;
+    }
+
+    Ok(Compaction {
+        entries: toc.len(),
+        writes: overlay.len(),
+        kept: records.len() - dropped,
+        dropped,
+        data_len: offset,
+    })
+}
+
+fn write_file(opts: &OpenOptions, dir: &Path, name: &str, buf: &[u8]) -> Result<(), io::Error> {
+    opts.open(dir.join(name))?.write_all(buf)
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    use super::compact;
+    use crate::overlay::Overlay;
+    use crate::toc::tests::write_toc;
+    use crate::toc::{Record, TableOfContents};
+
+    fn write_dataset(path: &Path) {
+        write_toc(path, &[[1; 16], [2; 16]], &[0, 4], &[4, 4], 4);
+        fs::write(path.join("protostore.data"), b"abcdefgh").unwrap();
+    }
+
+    #[test]
+    fn keep_versions() {
+        let input = TempDir::new("compact").unwrap();
+        let output = TempDir::new("compact").unwrap();
+        write_dataset(input.path());
+
+        {
+            let toc = TableOfContents::from_path(input.path()).unwrap();
+            let overlay = Overlay::open(input.path(), 8, true).unwrap();
+            let data = OpenOptions::new()
+                .write(true)
+                .open(input.path().join("protostore.data"))
+                .unwrap();
+            for value in &[&b"ijkl"[..], &b"mnop"[..]] {
+                let offset = overlay.reserve(4);
+                data.write_all_at(value, offset).unwrap();
+                let record = Record {
+                    offset,
+                    len: 4,
+                    checksum: None,
+                    flags: 0,
+                    expires_at: 0,
+                    version: 0,
+                };
+                overlay
+                    .commit(&[2; 16], toc.lookup(&[2; 16]), None, record, 0)
+                    .unwrap()
+                    .unwrap();
+            }
+        }
+
+        let compaction = compact(input.path(), output.path(), 0).unwrap();
+        assert_eq!(2, compaction.kept);
+        assert_eq!(8, compaction.data_len);
+
+        let toc = TableOfContents::from_path(output.path()).unwrap();
+        assert_eq!(1, toc.lookup(&[1; 16]).unwrap().version);
+        let written = toc.lookup(&[2; 16]).unwrap();
+        assert_eq!(3, written.version);
+
+        let data = fs::read(output.path().join("protostore.data")).unwrap();
+        let start = written.offset as usize;
+        assert_eq!(b"mnop", &data[start..start + 4]);
+
+        // In place would truncate the data file it reads from
+        assert!(compact(input.path(), input.path(), 0).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::info;
+
+use crate::backend::{DataFile, ReadBackend};
+use crate::overlay::Overlay;
+use crate::toc::{Record, TableOfContents};
+
+/// One generation of a dataset: the table of contents, the records
+/// written since it was built, and the data file they point into.
+#[derive(Debug)]
+pub struct Dataset {
+    pub path: PathBuf,
+    pub generation: u64,
+    pub toc: TableOfContents,
+    pub overlay: Overlay,
+    pub data: DataFile,
+}
+
+impl Dataset {
+    /// Opens the dataset at `path`, read-only unless `writable`.
+    pub fn open(
+        path: &Path,
+        generation: u64,
+        backend: ReadBackend,
+        writable: bool,
+    ) -> Result<Dataset, io::Error> {
+        let path = fs::canonicalize(path)?;
+        let toc = TableOfContents::from_path(&path)?;
+
+        let mut data_path = path.clone();
+        data_path.push("protostore.data");
+
+        // A partially copied dataset would have a data file that ends
+        // before the last record.
+        let data_len = data_path.metadata()?.len();
+        if data_len < toc.data_end() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data file has {} bytes but records end at {}",
+                    data_len,
+                    toc.data_end()
+                ),
+            ));
+        }
+
+        let overlay = Overlay::open(&path, data_len, writable)?;
+        let data = DataFile::open(&data_path, backend, writable)?;
+
+        Ok(Dataset {
+            path,
+            generation,
+            toc,
+            overlay,
+            data,
+        })
+    }
+
+    /// The current record for `uuid`, written ones win over the ones the
+    /// dataset was built with.
+    pub fn lookup(&self, uuid: &[u8; 16]) -> Option<Record> {
+        self.overlay.lookup(uuid).or_else(|| self.toc.lookup(uuid))
+    }
+
+    /// Current records from `start`, inclusive, to `end`, exclusive, in
+    /// uuid order. Written records replace the ones the dataset was
+    /// built with, and records written to new uuids are merged in.
+    pub fn scan<'a>(
+        &'a self,
+        start: &[u8; 16],
+        end: Option<&[u8; 16]>,
+    ) -> impl Iterator<Item = ([u8; 16], Record)> + 'a {
+        let mut built = self.toc.range(start, end).peekable();
+        let mut written = self.overlay.range(start, end).into_iter().peekable();
+        iter::from_fn(move || {
+            let next_built = built.peek().map(|index| self.toc.uuids()[*index]);
+            let next_written = written.peek().map(|(uuid, _)| *uuid);
+            match (next_built, next_written) {
+                (Some(built_uuid), Some(written_uuid)) if written_uuid <= built_uuid => {
+                    if written_uuid == built_uuid {
+                        built.next();
+                    }
+                    written.next()
+                }
+                (Some(built_uuid), _) => built
+                    .next()
+                    .map(|index| (built_uuid, self.toc.record(index))),
+                (None, _) => written.next(),
+            }
+        })
+    }
+}
+
+/// Holds the dataset generation new requests should use. Requests keep
+/// their generation alive for as long as they hold on to it, so a
+/// reload never pulls files from under an in-flight read.
+#[derive(Debug)]
+pub struct Datasets {
+    current: RwLock<Arc<Dataset>>,
+    // Held for the whole of a reload, so generations are handed out in
+    // the order datasets become current
+    last_generation: Mutex<u64>,
+    backend: ReadBackend,
+    writable: bool,
+}
+
+impl Datasets {
+    pub fn open(path: &Path, backend: ReadBackend, writable: bool) -> Result<Datasets, io::Error> {
+        let dataset = Dataset::open(path, 1, backend, writable)?;
+        info!(
+            "Reading {:?} with the {:?} backend",
+            dataset.path,
+            dataset.data.backend()
+        );
+        Ok(Datasets {
+            current: RwLock::new(Arc::new(dataset)),
+            last_generation: Mutex::new(1),
+            backend,
+            writable,
+        })
+    }
+
+    pub fn current(&self) -> Arc<Dataset> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Opens the dataset at `path` and makes it current. The previous
+    /// generation is closed once the reads still using it are done.
+    /// Refuses datasets that are missing records written to the current
+    /// generation, like ones compacted before the last writes.
+    pub fn reload(&self, path: &Path) -> Result<u64, io::Error> {
+        let mut last_generation = self.last_generation.lock().unwrap();
+        // A failed open doesn't use up a generation number
+        let generation = *last_generation + 1;
+        let dataset = Arc::new(Dataset::open(
+            path,
+            generation,
+            self.backend,
+            self.writable,
+        )?);
+        let (path, entries) = (dataset.path.clone(), dataset.toc.len());
+
+        let old = {
+            let mut current = self.current.write().unwrap();
+            // Commits that land from here on retry on the new generation
+            current.overlay.seal();
+            let missing = missing_writes(&current, &dataset);
+            if missing > 0 {
+                current.overlay.unseal();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} is missing {} records written to generation {}",
+                        dataset.path, missing, current.generation
+                    ),
+                ));
+            }
+            std::mem::replace(&mut *current, dataset)
+        };
+        *last_generation = generation;
+        info!(
+            "Loaded generation {} from {:?} with {} entries",
+            generation, path, entries
+        );
+
+        thread::spawn(move || {
+            while Arc::strong_count(&old) > 1 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            info!("Generation {} drained, closing it", old.generation);
+        });
+
+        Ok(generation)
+    }
+}
+
+// How many records written to `old` are not in `new` at the same or a
+// later version. Expired ones only count if `new` still has them.
+fn missing_writes(old: &Dataset, new: &Dataset) -> usize {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time drift!")
+        .as_secs();
+    old.overlay
+        .records()
+        .iter()
+        .filter(
+            |(uuid, written)| match new.lookup(uuid).filter(|r| !r.is_expired(now)) {
+                Some(record) => record.version < written.version,
+                None => !written.is_expired(now),
+            },
+        )
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    use super::{Dataset, Datasets};
+    use crate::backend::ReadBackend;
+    use crate::compact::compact;
+    use crate::toc::tests::write_toc;
+
+    fn write_dataset(path: &Path, value: &[u8]) {
+        write_toc(path, &[[1; 16]], &[0], &[value.len() as u32], 4);
+        fs::write(path.join("protostore.data"), value).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let first = TempDir::new("dataset").unwrap();
+        let second = TempDir::new("dataset").unwrap();
+        write_dataset(first.path(), b"abcd");
+        write_dataset(second.path(), b"abcdefgh");
+
+        let datasets = Datasets::open(first.path(), ReadBackend::Buffered, false).unwrap();
+        let old = datasets.current();
+        assert_eq!(1, old.generation);
+        assert_eq!(4, old.toc.max_len());
+
+        assert_eq!(2, datasets.reload(second.path()).unwrap());
+        assert_eq!(8, datasets.current().toc.max_len());
+
+        // Requests that started before the reload keep their generation
+        assert_eq!(4, old.toc.max_len());
+    }
+
+    #[test]
+    fn scan_written_records() {
+        let dir = TempDir::new("dataset").unwrap();
+        write_dataset(dir.path(), b"abcd");
+        let dataset = Dataset::open(dir.path(), 1, ReadBackend::Buffered, true).unwrap();
+
+        let mut record = dataset.toc.lookup(&[1; 16]).unwrap();
+        for uuid in &[[1; 16], [2; 16], [0; 16]] {
+            let base = dataset.toc.lookup(uuid);
+            dataset
+                .overlay
+                .commit(uuid, base, None, record, 0)
+                .unwrap()
+                .unwrap();
+        }
+        record.version = 2;
+
+        let scanned = dataset.scan(&[0; 16], None).collect::<Vec<_>>();
+        assert_eq!(3, scanned.len());
+        assert_eq!([0; 16], scanned[0].0);
+        assert_eq!(([1; 16], record), scanned[1]);
+        assert_eq!([2; 16], scanned[2].0);
+
+        let scanned = dataset.scan(&[1; 16], Some(&[2; 16])).collect::<Vec<_>>();
+        assert_eq!(vec![([1; 16], record)], scanned);
+    }
+
+    #[test]
+    fn refuse_losing_writes() {
+        let first = TempDir::new("dataset").unwrap();
+        let second = TempDir::new("dataset").unwrap();
+        let compacted = TempDir::new("dataset").unwrap();
+        write_dataset(first.path(), b"abcd");
+        write_dataset(second.path(), b"abcd");
+
+        let datasets = Datasets::open(first.path(), ReadBackend::Buffered, true).unwrap();
+        let dataset = datasets.current();
+        let base = dataset.toc.lookup(&[1; 16]);
+        dataset
+            .overlay
+            .commit(&[1; 16], base, None, base.unwrap(), 0)
+            .unwrap()
+            .unwrap();
+
+        // The write is only in the log of the first dataset
+        assert!(datasets.reload(second.path()).is_err());
+        assert_eq!(1, datasets.current().generation);
+        dataset
+            .overlay
+            .commit(&[1; 16], base, None, base.unwrap(), 0)
+            .unwrap()
+            .unwrap();
+
+        compact(first.path(), compacted.path(), 0).unwrap();
+        assert_eq!(2, datasets.reload(compacted.path()).unwrap());
+        assert_eq!(3, datasets.current().lookup(&[1; 16]).unwrap().version);
+    }
+
+    #[test]
+    fn reject_truncated_data() {
+        let first = TempDir::new("dataset").unwrap();
+        let second = TempDir::new("dataset").unwrap();
+        write_dataset(first.path(), b"abcd");
+        write_dataset(second.path(), b"abcd");
+        File::create(second.path().join("protostore.data")).unwrap();
+
+        let datasets = Datasets::open(first.path(), ReadBackend::Buffered, false).unwrap();
+        assert!(datasets.reload(second.path()).is_err());
+        assert_eq!(1, datasets.current().generation);
+
+        write_dataset(second.path(), b"abcd");
+        assert_eq!(2, datasets.reload(second.path()).unwrap());
+    }
+}
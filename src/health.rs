@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static HEALTH: Health = Health::new();
+
+/// Readiness of the process, reported to by the threads that load the
+/// dataset and run AIO sessions.
+pub fn health() -> &'static Health {
+    &HEALTH
+}
+
+#[derive(Debug)]
+pub struct Health {
+    toc_loaded: AtomicBool,
+    aio_expected: AtomicUsize,
+    aio_running: AtomicUsize,
+    draining: AtomicBool,
+}
+
+impl Health {
+    const fn new() -> Health {
+        Health {
+            toc_loaded: AtomicBool::new(false),
+            aio_expected: AtomicUsize::new(0),
+            aio_running: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn toc_loaded(&self) {
+        self.toc_loaded.store(true, Ordering::SeqCst);
+    }
+
+    /// How many AIO sessions have to be running to serve requests.
+    pub fn expect_aio_sessions(&self, count: usize) {
+        self.aio_expected.store(count, Ordering::SeqCst);
+    }
+
+    pub fn aio_started(&self) {
+        self.aio_running.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn aio_stopped(&self) {
+        self.aio_running.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Stops reporting ready so load balancers move traffic away before
+    /// the process exits.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Ok when the server can take traffic, otherwise why it can't.
+    pub fn ready(&self) -> Result<(), String> {
+        if self.draining() {
+            return Err("draining".to_owned());
+        }
+        if !self.toc_loaded.load(Ordering::SeqCst) {
+            return Err("loading table of contents".to_owned());
+        }
+        let expected = self.aio_expected.load(Ordering::SeqCst);
+        let running = self.aio_running.load(Ordering::SeqCst);
+        if running < expected {
+            return Err(format!("{} of {} aio sessions running", running, expected));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Health;
+
+    #[test]
+    fn ready() {
+        let health = Health::new();
+        health.expect_aio_sessions(2);
+        assert_eq!(Err("loading table of contents".to_owned()), health.ready());
+
+        health.toc_loaded();
+        health.aio_started();
+        assert_eq!(
+            Err("1 of 2 aio sessions running".to_owned()),
+            health.ready()
+        );
+        health.aio_started();
+        assert_eq!(Ok(()), health.ready());
+
+        // A failed session takes the server out of rotation
+        health.aio_stopped();
+        assert!(health.ready().is_err());
+        health.aio_started();
+
+        health.drain();
+        assert_eq!(Err("draining".to_owned()), health.ready());
+    }
+}
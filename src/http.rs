@@ -0,0 +1,146 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::cache::ValueCache;
+use crate::dataset::Datasets;
+use crate::health::health;
+use crate::latency;
+use crate::metrics::{metrics, write_metric};
+
+type Served = (Arc<Datasets>, Arc<ValueCache>);
+
+/// Serves `/metrics` in the Prometheus text format, `/live` and
+/// `/ready`. Scrapes and probes are rare and cheap, a thread of its own
+/// answering them one at a time is plenty.
+#[derive(Debug)]
+pub struct HttpServer {
+    // Set once the dataset is loaded, which can take minutes
+    served: RwLock<Option<Served>>,
+}
+
+impl HttpServer {
+    /// Starts answering on `addr` right away, so liveness probes pass
+    /// while the table of contents loads.
+    pub fn spawn(addr: SocketAddr) -> Result<Arc<HttpServer>, io::Error> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Serving metrics and health probes on http://{}", addr);
+        let server = Arc::new(HttpServer {
+            served: RwLock::new(None),
+        });
+        let http = server.clone();
+        thread::Builder::new()
+            .name("http".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| http.handle(stream)) {
+                        warn!("failed to serve http request; err = {:?}", e);
+                    }
+                }
+            })?;
+        Ok(server)
+    }
+
+    /// Adds the dataset and the cache to what `/metrics` reports.
+    pub fn serve(&self, datasets: Arc<Datasets>, cache: Arc<ValueCache>) {
+        *self.served.write().unwrap() = Some((datasets, cache));
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<(), io::Error> {
+        let served = self.served.read().unwrap().clone();
+        respond(stream, served.as_ref())
+    }
+}
+
+fn respond(mut stream: TcpStream, served: Option<&Served>) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers don't matter, but are read so closing doesn't reset
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? <= 2 {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", render_metrics(served)),
+        "/live" => ("200 OK", "ok\n".to_owned()),
+        "/ready" => match health().ready() {
+            Ok(()) => ("200 OK", "ready\n".to_owned()),
+            Err(reason) => ("503 Service Unavailable", format!("{}\n", reason)),
+        },
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn render_metrics(served: Option<&Served>) -> String {
+    let mut out = String::new();
+    metrics().render(&mut out);
+    latency::render(&mut out);
+    let (datasets, cache) = match served {
+        Some(served) => served,
+        None => return out,
+    };
+
+    let dataset = datasets.current();
+    let gauges = [
+        (
+            "dataset_generation",
+            "gauge",
+            "Generation of the dataset being served.",
+            dataset.generation as i64,
+        ),
+        (
+            "toc_entries",
+            "gauge",
+            "Entries in the table of contents.",
+            dataset.toc.len() as i64,
+        ),
+        (
+            "overlay_entries",
+            "gauge",
+            "Records written since the dataset was built.",
+            dataset.overlay.len() as i64,
+        ),
+        (
+            "cache_hits_total",
+            "counter",
+            "Reads answered from the value cache.",
+            cache.hits() as i64,
+        ),
+        (
+            "cache_misses_total",
+            "counter",
+            "Reads that had to go to disk.",
+            cache.misses() as i64,
+        ),
+        (
+            "cache_bytes",
+            "gauge",
+            "Bytes of values in the value cache.",
+            cache.size() as i64,
+        ),
+    ];
+    for (name, kind, help, value) in gauges.iter() {
+        write_metric(&mut out, name, kind, help, *value);
+    }
+    out
+}
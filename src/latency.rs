@@ -0,0 +1,205 @@
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+
+/// Stages of the read path that are timed separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    // Parsing a complete frame out of the socket buffer
+    Decode,
+    // Finding the offset and length of the value
+    Lookup,
+    // Waiting in the channel of the AIO session
+    Queue,
+    // From io_submit to the kernel completing the read, or the pread
+    // or copy out of the mapping of the other backends
+    Io,
+    // Writing the response out to the socket
+    Flush,
+}
+
+const STAGES: [&str; 5] = ["decode", "lookup", "queue", "io", "flush"];
+
+// Nanoseconds, up to a minute
+const MAX_LATENCY: u64 = 60_000_000_000;
+
+// Upper bounds of the exported buckets, in nanoseconds
+const BUCKETS: [u64; 16] = [
+    10_000,
+    25_000,
+    50_000,
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+];
+
+type Histograms = Vec<Histogram<u64>>;
+
+lazy_static! {
+    // Histograms of every thread that recorded anything
+    static ref THREADS: Mutex<Vec<Arc<Mutex<Histograms>>>> = Mutex::new(vec![]);
+}
+
+thread_local! {
+    // Only contended while being merged for an export
+    static LOCAL: Arc<Mutex<Histograms>> = {
+        let histograms = Arc::new(Mutex::new(new_histograms()));
+        THREADS.lock().unwrap().push(histograms.clone());
+        histograms
+    };
+}
+
+fn new_histograms() -> Histograms {
+    STAGES
+        .iter()
+        .map(|_| Histogram::new_with_bounds(1, MAX_LATENCY, 3).unwrap())
+        .collect()
+}
+
+/// Records the time spent in `stage` by one request.
+pub fn record(stage: Stage, elapsed: Duration) {
+    let nanos = elapsed.as_nanos() as u64;
+    LOCAL.with(|histograms| {
+        histograms.lock().unwrap()[stage as usize].saturating_record(nanos.max(1));
+    });
+}
+
+// Histograms of all threads added together, in the order of STAGES
+fn merged() -> Histograms {
+    let mut merged = new_histograms();
+    for histograms in THREADS.lock().unwrap().iter() {
+        let histograms = histograms.lock().unwrap();
+        for (total, histogram) in merged.iter_mut().zip(histograms.iter()) {
+            total.add(histogram).unwrap();
+        }
+    }
+    merged
+}
+
+/// Appends the latency of every stage as a Prometheus histogram. The
+/// buckets count every read since the server started, rates over them
+/// give the current latency.
+pub fn render(out: &mut String) {
+    render_histograms(out, &merged());
+}
+
+fn render_histograms(out: &mut String, histograms: &[Histogram<u64>]) {
+    writeln!(
+        out,
+        "# HELP protostore_read_stage_seconds Time reads spend in each stage.\n\
+         # TYPE protostore_read_stage_seconds histogram"
+    )
+    .unwrap();
+    for (stage, histogram) in STAGES.iter().zip(histograms.iter()) {
+        for bound in BUCKETS.iter() {
+            writeln!(
+                out,
+                "protostore_read_stage_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                stage,
+                seconds(*bound),
+                histogram.count_between(1, *bound)
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "protostore_read_stage_seconds_bucket{{stage=\"{}\",le=\"+Inf\"}} {}\n\
+             protostore_read_stage_seconds_sum{{stage=\"{}\"}} {}\n\
+             protostore_read_stage_seconds_count{{stage=\"{}\"}} {}",
+            stage,
+            histogram.len(),
+            stage,
+            histogram.mean() * histogram.len() as f64 / 1e9,
+            stage,
+            histogram.len()
+        )
+        .unwrap();
+    }
+}
+
+/// Percentiles of each stage in microseconds since the server started,
+/// for the `latency` admin command.
+pub fn report() -> Value {
+    report_histograms(&merged())
+}
+
+fn report_histograms(histograms: &[Histogram<u64>]) -> Value {
+    let mut report = Map::new();
+    for (stage, histogram) in STAGES.iter().zip(histograms.iter()) {
+        let micros = |quantile| histogram.value_at_quantile(quantile) as f64 / 1000.0;
+        report.insert(
+            stage.to_string(),
+            json!({
+                "count": histogram.len(),
+                "p50_us": micros(0.5),
+                "p90_us": micros(0.9),
+                "p99_us": micros(0.99),
+                "p999_us": micros(0.999),
+                "max_us": histogram.max() as f64 / 1000.0,
+            }),
+        );
+    }
+    Value::Object(report)
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{merged, record, render_histograms, report_histograms, Stage};
+
+    #[test]
+    fn merge_threads() {
+        let before = merged()[Stage::Io as usize].len();
+        let threads = (0..2)
+            .map(|_| thread::spawn(|| record(Stage::Io, Duration::from_micros(100))))
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Other tests may be recording at the same time
+        assert!(merged()[Stage::Io as usize].len() >= before + 2);
+    }
+
+    #[test]
+    fn render_and_report() {
+        let mut histograms = super::new_histograms();
+        histograms[Stage::Lookup as usize].record(2_000).unwrap();
+        histograms[Stage::Lookup as usize].record(4_000).unwrap();
+
+        let mut out = String::new();
+        render_histograms(&mut out, &histograms);
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert!(lines.contains(&"protostore_read_stage_seconds_count{stage=\"lookup\"} 2"));
+        assert!(lines.contains(&"protostore_read_stage_seconds_count{stage=\"flush\"} 0"));
+        let bucket = "protostore_read_stage_seconds_bucket{stage=\"lookup\",le=\"0.00001\"} 2";
+        assert!(lines.contains(&bucket));
+        let inf = "protostore_read_stage_seconds_bucket{stage=\"lookup\",le=\"+Inf\"} 2";
+        assert!(lines.contains(&inf));
+
+        let report = report_histograms(&histograms);
+        assert_eq!(2, report["lookup"]["count"]);
+        assert_eq!(2.0, report["lookup"]["p50_us"]);
+        assert_eq!(0, report["flush"]["count"]);
+    }
+}
@@ -1,9 +1,36 @@
 #![feature(async_await)]
 
+mod admin;
 mod aio;
+mod backend;
+mod cache;
+mod compact;
+mod dataset;
+mod health;
+mod http;
+mod latency;
+mod listener;
+mod metrics;
+mod overlay;
+mod placement;
 mod protocol;
 mod server;
+mod spans;
 mod toc;
 
-pub use server::ProtostoreServer;
-pub use toc::TableOfContents;
+pub use admin::Admin;
+pub use aio::{Session, SessionHandle};
+pub use backend::{DataFile, DataIo, ReadBackend};
+pub use cache::ValueCache;
+pub use compact::{compact, Compaction};
+pub use dataset::{Dataset, Datasets};
+pub use health::{health, Health};
+pub use http::HttpServer;
+pub use listener::{reuseport_listeners, steer_by_cpu};
+pub use metrics::{metrics, Metrics};
+pub use overlay::Overlay;
+pub use placement::{
+    allowed_cpus, cpu_quota, parse_cpu_list, plan_layout, thread_counts, Core, Layout, Slot,
+};
+pub use server::{ProtostoreServer, ServerConfig};
+pub use toc::{hash_key, Record, TableOfContents, FLAG_COMPRESSED, FLAG_KEYED};
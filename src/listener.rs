@@ -0,0 +1,156 @@
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+// Not every libc version has these
+const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+// BPF_ALU | BPF_MOD | BPF_K
+const BPF_ALU_MOD_K: u16 = 0x94;
+// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+// BPF_RET | BPF_A
+const BPF_RET_A: u16 = 0x16;
+const SKF_AD_CPU: u32 = (-0x1000i32 + 36) as u32;
+
+/// `count` listeners bound to the same address with SO_REUSEPORT, so
+/// the kernel spreads new connections among them. With port 0 they all
+/// share the port picked for the first one.
+pub fn reuseport_listeners(addr: &SocketAddr, count: usize) -> io::Result<Vec<net::TcpListener>> {
+    let mut listeners = Vec::with_capacity(count);
+    let mut addr = *addr;
+    for _ in 0..count {
+        let listener = reuseport_listener(&addr)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Hands each connection to the listener of the thread running on the
+/// CPU that received it. `cpus[i]` is the OS id of the CPU the owner of
+/// the `i`th listener, in the order they were bound, is pinned to.
+/// Connections received on other CPUs go to the listener at their CPU
+/// id modulo the group size.
+pub fn steer_by_cpu(listener: &net::TcpListener, cpus: &[usize]) -> io::Result<()> {
+    let mut program = Vec::with_capacity(2 * cpus.len() + 3);
+    program.push(filter(BPF_LD_W_ABS, 0, 0, SKF_AD_CPU));
+    for (index, cpu) in cpus.iter().enumerate() {
+        // Falls through to the next CPU unless this one matches
+        program.push(filter(BPF_JMP_JEQ_K, 0, 1, *cpu as u32));
+        program.push(filter(BPF_RET_K, 0, 0, index as u32));
+    }
+    program.push(filter(BPF_ALU_MOD_K, 0, 0, cpus.len() as u32));
+    program.push(filter(BPF_RET_A, 0, 0, 0));
+
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    setsockopt(listener.as_raw_fd(), SO_ATTACH_REUSEPORT_CBPF, &fprog)
+}
+
+fn filter(code: u16, jt: u8, jf: u8, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+fn reuseport_listener(addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owns the socket from here on, so it's closed on errors
+    let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+
+    let on: libc::c_int = 1;
+    setsockopt(fd, libc::SO_REUSEADDR, &on)?;
+    setsockopt(fd, libc::SO_REUSEPORT, &on)?;
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&sockaddr) as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::listen(fd, 1024) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+fn setsockopt<T>(fd: RawFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::{reuseport_listeners, steer_by_cpu};
+
+    #[test]
+    fn share_port() {
+        let listeners = reuseport_listeners(&"127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let first = listeners[0].local_addr().unwrap();
+        assert_ne!(0, first.port());
+        assert_eq!(first, listeners[1].local_addr().unwrap());
+
+        steer_by_cpu(&listeners[0], &[3, 1]).unwrap();
+        TcpStream::connect(first).unwrap();
+    }
+}
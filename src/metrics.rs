@@ -0,0 +1,274 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+use crate::protocol::{RequestType, Status};
+
+const REQUEST_TYPES: [&str; 7] = [
+    "read",
+    "write",
+    "capabilities",
+    "scan",
+    "append",
+    "admin",
+    "ping",
+];
+const STATUSES: [&str; 4] = ["ok", "corrupt", "conflict", "read_only"];
+
+// Array elements can't be repeated from a non-Copy value, every use of
+// this is a new counter
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+static METRICS: Metrics = Metrics::new();
+
+/// Counters shared by every thread of the process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    // By request type, then status
+    requests: [[AtomicUsize; 4]; 7],
+    toc_hits: AtomicUsize,
+    toc_misses: AtomicUsize,
+    corrupt_values: AtomicUsize,
+    bytes_served: AtomicUsize,
+    connections: AtomicUsize,
+    open_connections: AtomicIsize,
+    aio_submits: AtomicUsize,
+    aio_submitted_ops: AtomicUsize,
+    aio_completions: AtomicUsize,
+    aio_inflight: AtomicIsize,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            requests: [
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+                [ZERO, ZERO, ZERO, ZERO],
+            ],
+            toc_hits: ZERO,
+            toc_misses: ZERO,
+            corrupt_values: ZERO,
+            bytes_served: ZERO,
+            connections: ZERO,
+            open_connections: AtomicIsize::new(0),
+            aio_submits: ZERO,
+            aio_submitted_ops: ZERO,
+            aio_completions: ZERO,
+            aio_inflight: AtomicIsize::new(0),
+        }
+    }
+
+    pub fn request(&self, reqtype: &RequestType, status: Status, body_len: usize) {
+        let reqtype = match reqtype {
+            RequestType::Read => 0,
+            RequestType::Write => 1,
+            RequestType::Capabilities => 2,
+            RequestType::Scan => 3,
+            RequestType::Append => 4,
+            RequestType::Admin => 5,
+            RequestType::Ping => 6,
+        };
+        self.requests[reqtype][status.code() as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(body_len, Ordering::Relaxed);
+    }
+
+    pub fn toc_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.toc_hits
+        } else {
+            &self.toc_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn corrupt_value(&self) {
+        self.corrupt_values.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn corrupt_values(&self) -> usize {
+        self.corrupt_values.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn open_connections(&self) -> isize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// One `io_submit` of a batch of `ops` requests.
+    pub fn aio_submit(&self, ops: usize) {
+        self.aio_submits.fetch_add(1, Ordering::Relaxed);
+        self.aio_submitted_ops.fetch_add(ops, Ordering::Relaxed);
+    }
+
+    pub fn aio_queued(&self) {
+        self.aio_inflight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn aio_completed(&self) {
+        self.aio_completions.fetch_add(1, Ordering::Relaxed);
+        self.aio_inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn aio_submits(&self) -> usize {
+        self.aio_submits.load(Ordering::Relaxed)
+    }
+
+    pub fn aio_submitted_ops(&self) -> usize {
+        self.aio_submitted_ops.load(Ordering::Relaxed)
+    }
+
+    pub fn aio_completions(&self) -> usize {
+        self.aio_completions.load(Ordering::Relaxed)
+    }
+
+    pub fn aio_inflight(&self) -> isize {
+        self.aio_inflight.load(Ordering::Relaxed)
+    }
+
+    /// Appends every metric in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        writeln!(
+            out,
+            "# HELP protostore_requests_total Requests handled, by type and status.\n\
+             # TYPE protostore_requests_total counter"
+        )
+        .unwrap();
+        for (reqtype, statuses) in REQUEST_TYPES.iter().zip(self.requests.iter()) {
+            for (status, count) in STATUSES.iter().zip(statuses.iter()) {
+                writeln!(
+                    out,
+                    "protostore_requests_total{{type=\"{}\",status=\"{}\"}} {}",
+                    reqtype,
+                    status,
+                    count.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP protostore_toc_lookups_total Lookups of requested uuids, by result.\n\
+             # TYPE protostore_toc_lookups_total counter\n\
+             protostore_toc_lookups_total{{result=\"hit\"}} {}\n\
+             protostore_toc_lookups_total{{result=\"miss\"}} {}",
+            self.toc_hits.load(Ordering::Relaxed),
+            self.toc_misses.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as i64;
+        let metrics = [
+            (
+                "corrupt_values_total",
+                "counter",
+                "Values that failed checksum verification.",
+                load(&self.corrupt_values),
+            ),
+            (
+                "bytes_served_total",
+                "counter",
+                "Bytes of response bodies sent to clients.",
+                load(&self.bytes_served),
+            ),
+            (
+                "connections_total",
+                "counter",
+                "Client connections accepted.",
+                load(&self.connections),
+            ),
+            (
+                "open_connections",
+                "gauge",
+                "Client connections currently open.",
+                self.open_connections.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "aio_submits_total",
+                "counter",
+                "Batches submitted to the kernel with io_submit.",
+                load(&self.aio_submits),
+            ),
+            (
+                "aio_submitted_ops_total",
+                "counter",
+                "Reads and writes submitted, over aio_submits_total is the batch size.",
+                load(&self.aio_submitted_ops),
+            ),
+            (
+                "aio_completions_total",
+                "counter",
+                "Reads and writes completed by the kernel.",
+                load(&self.aio_completions),
+            ),
+            (
+                "aio_inflight",
+                "gauge",
+                "Reads and writes submitted and not completed yet.",
+                self.aio_inflight.load(Ordering::Relaxed) as i64,
+            ),
+        ];
+        for (name, kind, help, value) in metrics.iter() {
+            write_metric(out, name, kind, help, *value);
+        }
+    }
+}
+
+/// Appends a single unlabeled metric in the Prometheus text format.
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: i64) {
+    writeln!(
+        out,
+        "# HELP protostore_{} {}\n# TYPE protostore_{} {}\nprotostore_{} {}",
+        name, help, name, kind, name, value
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::protocol::{RequestType, Status};
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.request(&RequestType::Scan, Status::Corrupt, 10);
+        metrics.request(&RequestType::Scan, Status::Corrupt, 5);
+        metrics.connection_opened();
+        metrics.aio_queued();
+        metrics.aio_queued();
+        metrics.aio_completed();
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+        let lines = out.lines().collect::<Vec<&str>>();
+
+        assert!(lines.contains(&"protostore_requests_total{type=\"scan\",status=\"corrupt\"} 2"));
+        assert!(lines.contains(&"protostore_requests_total{type=\"read\",status=\"ok\"} 0"));
+        assert!(lines.contains(&"protostore_bytes_served_total 15"));
+        assert!(lines.contains(&"protostore_open_connections 1"));
+        assert!(lines.contains(&"protostore_aio_inflight 1"));
+        assert!(lines.contains(&"# TYPE protostore_aio_inflight gauge"));
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use bytes::{ByteOrder, LittleEndian};
+use log::info;
+
+use crate::toc::Record;
+
+// uuid, offset, len, checksum, flags, expires_at, version
+const LOG_ENTRY_LEN: usize = 16 + 8 + 4 + 4 + 1 + 8 + 8;
+
+/// Records written since the dataset was built. Values are appended to
+/// the data file and their records to `protostore.log`, which is
+/// replayed on open so writes survive restarts until the next compaction.
+#[derive(Debug)]
+pub struct Overlay {
+    records: RwLock<HashMap<[u8; 16], Record>>,
+    // Serializes commits, and holds where values are written
+    log: Mutex<Log>,
+    // Serializes syncs of the log
+    synced: Mutex<Synced>,
+}
+
+#[derive(Debug)]
+struct Log {
+    // None unless writes are enabled
+    file: Option<File>,
+    // End of the space handed out for values
+    next_offset: u64,
+    // Space handed out for values that were never committed, as
+    // aligned offset and length
+    free: Vec<(u64, u64)>,
+    // Set once another generation is taking over
+    sealed: bool,
+    // Entries appended since the log was opened
+    entries: u64,
+}
+
+#[derive(Debug)]
+struct Synced {
+    // A handle of its own, so appends go on while it syncs
+    file: Option<File>,
+    // Entries known to be on disk
+    entries: u64,
+}
+
+impl Overlay {
+    /// Replays the log at `path` if there is one. The log is only
+    /// created, and commits only accepted, when `writable`.
+    pub fn open(path: &Path, data_len: u64, writable: bool) -> Result<Overlay, io::Error> {
+        let log_path = path.join("protostore.log");
+
+        let mut records = HashMap::new();
+        let mut next_offset = align(data_len);
+        if log_path.exists() {
+            // A crash can leave a partial entry at the end, it's skipped
+            let log = fs::read(&log_path)?;
+            for entry in log.chunks_exact(LOG_ENTRY_LEN) {
+                let (uuid, record) = decode_entry(entry);
+                next_offset = next_offset.max(align(record.offset + record.len as u64));
+                records.insert(uuid, record);
+            }
+            info!("Replayed {} writes from {:?}", records.len(), log_path);
+        }
+
+        let log = if writable {
+            Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log_path)?,
+            )
+        } else {
+            None
+        };
+        let sync_file = match log {
+            Some(ref file) => Some(file.try_clone()?),
+            None => None,
+        };
+
+        Ok(Overlay {
+            records: RwLock::new(records),
+            log: Mutex::new(Log {
+                file: log,
+                next_offset,
+                free: vec![],
+                sealed: false,
+                entries: 0,
+            }),
+            synced: Mutex::new(Synced {
+                file: sync_file,
+                entries: 0,
+            }),
+        })
+    }
+
+    pub fn writable(&self) -> bool {
+        self.log.lock().unwrap().file.is_some()
+    }
+
+    pub fn lookup(&self, uuid: &[u8; 16]) -> Option<Record> {
+        self.records.read().unwrap().get(uuid).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.read().unwrap().len()
+    }
+
+    /// Every written record, in no particular order.
+    pub fn records(&self) -> Vec<([u8; 16], Record)> {
+        self.records
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(uuid, record)| (*uuid, *record))
+            .collect()
+    }
+
+    /// Written records from `start`, inclusive, to `end`, exclusive, in
+    /// uuid order.
+    pub fn range(&self, start: &[u8; 16], end: Option<&[u8; 16]>) -> Vec<([u8; 16], Record)> {
+        let mut records = self
+            .records
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(uuid, _)| *uuid >= start && end.map_or(true, |end| *uuid < end))
+            .map(|(uuid, record)| (*uuid, *record))
+            .collect::<Vec<_>>();
+        records.sort_by_key(|(uuid, _)| *uuid);
+        records
+    }
+
+    /// Space for a value of `len` bytes, reused from a write that
+    /// didn't commit or at the end of the data file. Offsets and lengths
+    /// are aligned for O_DIRECT.
+    pub fn reserve(&self, len: u64) -> u64 {
+        let len = align(len);
+        let mut log = self.log.lock().unwrap();
+        if let Some(index) = log.free.iter().position(|&(_, free)| free >= len) {
+            let (offset, free) = log.free[index];
+            if free == len {
+                log.free.swap_remove(index);
+            } else {
+                log.free[index] = (offset + len, free - len);
+            }
+            return offset;
+        }
+
+        let offset = log.next_offset;
+        log.next_offset += len;
+        offset
+    }
+
+    /// Hands back space from `reserve` when the value written there
+    /// didn't commit, so losing a compare-and-set doesn't leak it.
+    pub fn release(&self, offset: u64, len: u64) {
+        let len = align(len);
+        let mut log = self.log.lock().unwrap();
+        if offset + len == log.next_offset {
+            log.next_offset = offset;
+        } else {
+            log.free.push((offset, len));
+        }
+    }
+
+    /// Stops taking commits, they fail with `Interrupted` so they can be
+    /// retried on the generation that replaces this one.
+    pub fn seal(&self) {
+        self.log.lock().unwrap().sealed = true;
+    }
+
+    /// Takes commits again after a replacement fell through.
+    pub fn unseal(&self) {
+        self.log.lock().unwrap().sealed = false;
+    }
+
+    /// Makes `record` the current one for `uuid`, if the version of
+    /// the current one is `expected`. `base` is the record the dataset
+    /// was built with, if any. Returns the new version, or the current
+    /// one when it didn't match. The value has to be synced to the data
+    /// file already. Blocks until the log entry is on disk, so it belongs
+    /// on the blocking pool.
+    pub fn commit(
+        &self,
+        uuid: &[u8; 16],
+        base: Option<Record>,
+        expected: Option<u64>,
+        mut record: Record,
+        now: u64,
+    ) -> Result<Result<u64, u64>, io::Error> {
+        let (version, entry) = {
+            let mut log = self.log.lock().unwrap();
+            if log.sealed {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "dataset generation was replaced",
+                ));
+            }
+            let file = match log.file {
+                Some(ref mut file) => file,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "dataset was opened without writes enabled",
+                    ));
+                }
+            };
+
+            let current = self
+                .lookup(uuid)
+                .or(base)
+                .filter(|record| !record.is_expired(now))
+                .map_or(0, |record| record.version);
+            if expected.map_or(false, |expected| expected != current) {
+                return Ok(Err(current));
+            }
+
+            // Readers see the record before the sync below is done, but
+            // the writer only hears back once it is
+            record.version = current + 1;
+            file.write_all(&encode_entry(uuid, &record))?;
+            log.entries += 1;
+            self.records.write().unwrap().insert(*uuid, record);
+            (record.version, log.entries)
+        };
+
+        // Acknowledged writes have to survive a crash. Commits waiting
+        // here while another one syncs share the next sync.
+        self.sync(entry)?;
+        Ok(Ok(version))
+    }
+
+    // Makes sure the log is on disk up to `entry`.
+    fn sync(&self, entry: u64) -> Result<(), io::Error> {
+        let mut synced = self.synced.lock().unwrap();
+        if synced.entries >= entry {
+            return Ok(());
+        }
+        let appended = self.log.lock().unwrap().entries;
+        if let Some(ref file) = synced.file {
+            file.sync_data()?;
+        }
+        synced.entries = appended;
+        Ok(())
+    }
+}
+
+pub fn align(len: u64) -> u64 {
+    (len + 511) / 512 * 512
+}
+
+fn encode_entry(uuid: &[u8; 16], record: &Record) -> [u8; LOG_ENTRY_LEN] {
+    let mut entry = [0; LOG_ENTRY_LEN];
+    entry[..16].copy_from_slice(uuid);
+    LittleEndian::write_u64(&mut entry[16..24], record.offset);
+    LittleEndian::write_u32(&mut entry[24..28], record.len);
+    LittleEndian::write_u32(&mut entry[28..32], record.checksum.unwrap_or(0));
+    entry[32] = record.flags;
+    LittleEndian::write_u64(&mut entry[33..41], record.expires_at);
+    LittleEndian::write_u64(&mut entry[41..49], record.version);
+    entry
+}
+
+fn decode_entry(entry: &[u8]) -> ([u8; 16], Record) {
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&entry[..16]);
+    let record = Record {
+        offset: LittleEndian::read_u64(&entry[16..24]),
+        len: LittleEndian::read_u32(&entry[24..28]),
+        checksum: Some(LittleEndian::read_u32(&entry[28..32])),
+        flags: entry[32],
+        expires_at: LittleEndian::read_u64(&entry[33..41]),
+        version: LittleEndian::read_u64(&entry[41..49]),
+    };
+    (uuid, record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::thread;
+    use tempdir::TempDir;
+
+    use super::Overlay;
+    use crate::toc::Record;
+
+    fn record(offset: u64) -> Record {
+        Record {
+            offset,
+            len: 4,
+            checksum: Some(0xcafebabe),
+            flags: 0,
+            expires_at: 0,
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn reserve_aligned() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 1000, true).unwrap();
+
+        assert_eq!(1024, overlay.reserve(4));
+        assert_eq!(1536, overlay.reserve(513));
+        assert_eq!(2560, overlay.reserve(512));
+    }
+
+    #[test]
+    fn release() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+
+        let first = overlay.reserve(1024);
+        let second = overlay.reserve(512);
+        overlay.release(first, 1024);
+        assert_eq!(0, overlay.reserve(4));
+        assert_eq!(512, overlay.reserve(512));
+
+        // The end of the data file is handed out again
+        overlay.release(second, 512);
+        assert_eq!(1024, overlay.reserve(512));
+        assert_eq!(1536, overlay.reserve(512));
+    }
+
+    #[test]
+    fn compare_and_set() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+        let uuid = [1; 16];
+
+        // Only if absent
+        assert_eq!(
+            Ok(1),
+            overlay.commit(&uuid, None, Some(0), record(0), 0).unwrap()
+        );
+        assert_eq!(
+            Err(1),
+            overlay
+                .commit(&uuid, None, Some(0), record(512), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            Ok(2),
+            overlay
+                .commit(&uuid, None, Some(1), record(512), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            Ok(3),
+            overlay.commit(&uuid, None, None, record(1024), 0).unwrap()
+        );
+
+        // Records the dataset was built with are at version 1
+        let other = [2; 16];
+        let mut base = record(0);
+        base.version = 1;
+        assert_eq!(
+            Err(1),
+            overlay
+                .commit(&other, Some(base), Some(0), record(0), 0)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn replay() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let uuid = [1; 16];
+        {
+            let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+            let offset = overlay.reserve(4);
+            overlay
+                .commit(&uuid, None, None, record(offset), 0)
+                .unwrap()
+                .unwrap();
+        }
+
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+        let mut expected = record(0);
+        expected.version = 1;
+        assert_eq!(Some(expected), overlay.lookup(&uuid));
+        assert_eq!(512, overlay.reserve(4));
+    }
+
+    #[test]
+    fn concurrent_commits() {
+        let tmp = TempDir::new("overlay").unwrap();
+        {
+            let overlay = Arc::new(Overlay::open(tmp.path(), 0, true).unwrap());
+            let threads = (0..8u8)
+                .map(|i| {
+                    let overlay = overlay.clone();
+                    thread::spawn(move || {
+                        for _ in 0..10 {
+                            overlay
+                                .commit(&[i; 16], None, None, record(0), 0)
+                                .unwrap()
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+        for i in 0..8u8 {
+            assert_eq!(10, overlay.lookup(&[i; 16]).unwrap().version);
+        }
+    }
+
+    #[test]
+    fn range() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+        for uuid in &[[3; 16], [1; 16], [2; 16]] {
+            overlay
+                .commit(uuid, None, None, record(0), 0)
+                .unwrap()
+                .unwrap();
+        }
+
+        let uuids = |records: Vec<([u8; 16], _)>| {
+            records
+                .into_iter()
+                .map(|(uuid, _)| uuid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![[1; 16], [2; 16], [3; 16]],
+            uuids(overlay.range(&[0; 16], None))
+        );
+        assert_eq!(
+            vec![[2; 16]],
+            uuids(overlay.range(&[2; 16], Some(&[3; 16])))
+        );
+    }
+
+    #[test]
+    fn sealed() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 0, true).unwrap();
+        overlay.seal();
+        let err = overlay
+            .commit(&[1; 16], None, None, record(0), 0)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+
+        overlay.unseal();
+        assert_eq!(
+            Ok(1),
+            overlay.commit(&[1; 16], None, None, record(0), 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let tmp = TempDir::new("overlay").unwrap();
+        let overlay = Overlay::open(tmp.path(), 0, false).unwrap();
+        assert!(!overlay.writable());
+        assert!(overlay.commit(&[1; 16], None, None, record(0), 0).is_err());
+        assert!(!tmp.path().join("protostore.log").exists());
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+use std::fs;
+use std::mem;
+
+/// A physical core: the NUMA node it is on and the indexes of its
+/// processing units, as used for binding threads.
+#[derive(Debug, Clone)]
+pub struct Core {
+    pub node: usize,
+    pub pus: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+    pub pu: usize,
+    pub node: usize,
+}
+
+/// Processing units picked for each thread of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub main: Slot,
+    pub aio: Vec<Slot>,
+    pub tcp: Vec<Slot>,
+}
+
+/// Places the main thread and the AIO threads on the NUMA node of the
+/// storage device, and the TCP threads on the node of the NIC. Each
+/// thread gets a physical core of its own while there are any left,
+/// then sibling hyperthreads, then threads start sharing.
+pub fn plan_layout(
+    cores: &[Core],
+    storage_node: Option<usize>,
+    nic_node: Option<usize>,
+    num_aio: usize,
+    num_tcp: usize,
+) -> Layout {
+    // First processing unit of every core, then the siblings
+    let max_pus = cores.iter().map(|core| core.pus.len()).max().unwrap_or(0);
+    let all = (0..max_pus)
+        .flat_map(|i| {
+            cores.iter().filter_map(move |core| {
+                core.pus.get(i).map(|pu| Slot {
+                    pu: *pu,
+                    node: core.node,
+                })
+            })
+        })
+        .collect::<Vec<Slot>>();
+
+    let mut free = all.clone();
+    let main = take(&mut free, &[], storage_node).unwrap_or(Slot { pu: 0, node: 0 });
+    let shared = all
+        .iter()
+        .cloned()
+        .filter(|slot| *slot != main)
+        .collect::<Vec<Slot>>();
+
+    let aio = (0..num_aio)
+        .map(|_| take(&mut free, &shared, storage_node).unwrap_or(main))
+        .collect();
+    let tcp = (0..num_tcp)
+        .map(|_| take(&mut free, &shared, nic_node.or(storage_node)).unwrap_or(main))
+        .collect();
+
+    Layout { main, aio, tcp }
+}
+
+// The first free slot on `node`, or on any node if there are none.
+// Once all are taken they are handed out again from `shared`.
+fn take(free: &mut Vec<Slot>, shared: &[Slot], node: Option<usize>) -> Option<Slot> {
+    if free.is_empty() {
+        free.extend_from_slice(shared);
+    }
+    let index = node
+        .and_then(|node| free.iter().position(|slot| slot.node == node))
+        .unwrap_or(0);
+    if index < free.len() {
+        Some(free.remove(index))
+    } else {
+        None
+    }
+}
+
+/// How many AIO and TCP threads to run with `cpus` CPUs to spare. The
+/// main thread is mostly idle and isn't counted against them.
+pub fn thread_counts(cpus: usize, thread_per_core: bool) -> (usize, usize) {
+    if thread_per_core {
+        return (0, cpus.max(1));
+    }
+    let aio = if cpus >= 6 { 2 } else { 1 };
+    let tcp = cpus.saturating_sub(aio + 1).max(1).min(8);
+    (aio, tcp)
+}
+
+/// CPUs the process may run on: its affinity mask, narrowed down by
+/// the cgroup cpuset if there is one.
+pub fn allowed_cpus() -> Vec<usize> {
+    let mut cpus = unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) == 0 {
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+                .collect()
+        } else {
+            vec![]
+        }
+    };
+
+    let cpuset = [
+        "/sys/fs/cgroup/cpuset.cpus.effective",
+        "/sys/fs/cgroup/cpuset/cpuset.effective_cpus",
+    ]
+    .iter()
+    .filter_map(|path| fs::read_to_string(path).ok())
+    .map(|list| parse_cpu_list(&list))
+    .find(|list| !list.is_empty());
+    if let Some(cpuset) = cpuset {
+        if cpus.is_empty() {
+            cpus = cpuset;
+        } else {
+            cpus.retain(|cpu| cpuset.contains(cpu));
+        }
+    }
+    cpus
+}
+
+/// CPUs worth of time the cgroup quota allows, None when unlimited.
+pub fn cpu_quota() -> Option<f64> {
+    if let Ok(max) = fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        let mut fields = max.split_whitespace();
+        let quota = fields.next()?.parse::<f64>().ok()?;
+        let period = fields.next()?.parse::<f64>().ok()?;
+        return Some(quota / period);
+    }
+
+    let read = |name| fs::read_to_string(format!("/sys/fs/cgroup/cpu/{}", name)).ok();
+    let quota = read("cpu.cfs_quota_us")?.trim().parse::<f64>().ok()?;
+    let period = read("cpu.cfs_period_us")?.trim().parse::<f64>().ok()?;
+    if quota < 0.0 {
+        None
+    } else {
+        Some(quota / period)
+    }
+}
+
+/// Parses cpu lists as found in sysfs and cgroups, like `0-3,8`.
+pub fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|cpu| cpu.parse::<usize>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), Some(Ok(last))) => cpus.extend(first..=last),
+            (Some(Ok(cpu)), None) => cpus.push(cpu),
+            _ => (),
+        }
+    }
+    cpus
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let slots = |slots: &[Slot]| {
+            slots
+                .iter()
+                .map(|slot| format!("{}@{}", slot.pu, slot.node))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        write!(
+            f,
+            "main pu {}@{}, aio pus [{}], tcp pus [{}] (pu@numa node)",
+            self.main.pu,
+            self.main.node,
+            slots(&self.aio),
+            slots(&self.tcp)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_list, plan_layout, thread_counts, Core, Slot};
+
+    // Two nodes with two cores each, two hyperthreads per core
+    fn cores() -> Vec<Core> {
+        vec![
+            Core {
+                node: 0,
+                pus: vec![0, 1],
+            },
+            Core {
+                node: 0,
+                pus: vec![2, 3],
+            },
+            Core {
+                node: 1,
+                pus: vec![4, 5],
+            },
+            Core {
+                node: 1,
+                pus: vec![6, 7],
+            },
+        ]
+    }
+
+    fn pus(slots: &[Slot]) -> Vec<usize> {
+        slots.iter().map(|slot| slot.pu).collect()
+    }
+
+    #[test]
+    fn prefer_device_nodes() {
+        let layout = plan_layout(&cores(), Some(1), Some(0), 1, 2);
+        assert_eq!(4, layout.main.pu);
+        assert_eq!(vec![6], pus(&layout.aio));
+        assert_eq!(vec![0, 2], pus(&layout.tcp));
+    }
+
+    #[test]
+    fn physical_cores_first() {
+        let layout = plan_layout(&cores(), None, None, 1, 4);
+        assert_eq!(0, layout.main.pu);
+        assert_eq!(vec![2], pus(&layout.aio));
+        assert_eq!(vec![4, 6, 1, 3], pus(&layout.tcp));
+    }
+
+    #[test]
+    fn share_when_out_of_pus() {
+        let layout = plan_layout(&cores(), None, None, 0, 9);
+        assert_eq!(0, layout.main.pu);
+        assert_eq!(vec![2, 4, 6, 1, 3, 5, 7, 2, 4], pus(&layout.tcp));
+    }
+
+    #[test]
+    fn size_threads_to_cpus() {
+        assert_eq!((1, 1), thread_counts(1, false));
+        assert_eq!((1, 2), thread_counts(4, false));
+        assert_eq!((2, 8), thread_counts(64, false));
+        assert_eq!((0, 3), thread_counts(3, true));
+    }
+
+    #[test]
+    fn parse_cpus() {
+        assert_eq!(vec![0, 1, 2, 3, 8], parse_cpu_list("0-3,8\n"));
+        assert_eq!(Vec::<usize>::new(), parse_cpu_list(""));
+    }
+}
@@ -1,12 +1,29 @@
+use std::cmp;
 use std::io;
+use std::time::Instant;
 
-use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
+use bytes::{BufMut, Bytes, BytesMut};
 use tokio::codec::{Decoder, Encoder};
 
-#[derive(Debug, PartialEq)]
+use crate::latency::{self, Stage};
+use crate::toc::hash_key;
+
+use protostore_client::Frame;
+
+pub use protostore_client::codec::{
+    Response, Status, CAP_COMPRESSED, CAP_VERSIONS, EXTENDED_LEN, STATUS_LEN,
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum RequestType {
     Read,
     Write,
+    Capabilities,
+    Scan,
+    Append,
+    // Operator command, its name is the body
+    Admin,
+    Ping,
 }
 
 #[derive(Debug)]
@@ -14,22 +31,48 @@ pub struct Request {
     pub reqtype: RequestType,
     pub id: u32,
     pub uuid: [u8; 16],
-    pub body: Option<BytesMut>,
+    pub flags: u32,
+    // Set for requests by byte-string key, `uuid` is then its hash
+    pub key: Option<Bytes>,
+    pub scan: Option<Scan>,
+    // Seconds the written value should live for
+    pub ttl: Option<u32>,
+    // Version the record must be at for a conditional write to go
+    // through, 0 meaning the key must be absent
+    pub expected_version: Option<u64>,
+    pub body: Option<Bytes>,
 }
 
-#[derive(Debug)]
-pub struct Response {
-    pub id: u32,
-    pub body: Bytes,
+impl Request {
+    pub fn new(reqtype: RequestType, id: u32, uuid: [u8; 16]) -> Request {
+        Request {
+            reqtype,
+            id,
+            uuid,
+            flags: 0,
+            key: None,
+            scan: None,
+            ttl: None,
+            expected_version: None,
+            body: None,
+        }
+    }
 }
 
-pub struct Protocol {
-    pub len: Option<usize>,
+/// Range of a scan request. It starts at the request uuid, inclusive.
+#[derive(Debug, PartialEq)]
+pub struct Scan {
+    // Exclusive, None scans to the end of the keyspace
+    pub end: Option<[u8; 16]>,
+    pub limit: u32,
 }
 
+/// Reads requests off a connection and writes responses to it.
+pub struct Protocol;
+
 impl Protocol {
     pub fn new() -> Protocol {
-        Protocol { len: None }
+        Protocol
     }
 }
 
@@ -38,60 +81,124 @@ impl Decoder for Protocol {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
-        if buf.len() < 4 {
-            return Ok(None);
-        }
-
-        if self.len.is_none() {
-            let len = buf.split_to(4).into_buf().get_u32_be();
-            self.len = Some(len as usize);
+        let start = Instant::now();
+        let request = Frame::decode(buf)?.map(request);
+        if let Some(Request {
+            reqtype: RequestType::Read,
+            ..
+        }) = request
+        {
+            latency::record(Stage::Decode, start.elapsed());
         }
+        Ok(request)
+    }
+}
 
-        if buf.len() < self.len.unwrap() {
-            return Ok(None);
+fn request(frame: Frame) -> Request {
+    match frame {
+        Frame::Read { id, uuid } => Request::new(RequestType::Read, id, uuid),
+        Frame::ReadKey { id, key } => {
+            let uuid = hash_key(&key);
+            Request {
+                key: Some(key),
+                ..Request::new(RequestType::Read, id, uuid)
+            }
         }
-
-        match buf[0] {
-            b'W' => {
-                let mut header = buf.split_to(1 + 16 + 4).into_buf();
-                header.advance(1); // skip type
-
-                let mut uuid: [u8; 16] = [0; 16];
-                header.copy_to_slice(&mut uuid);
-                let id = header.get_u32_be();
-                let body = buf.split_to(self.len.unwrap() - (1 + 16 + 4));
-
-                self.len = None;
-                Ok(Some(Request {
-                    reqtype: RequestType::Write,
-                    id: id,
-                    uuid: uuid,
-                    body: Some(body),
-                }))
+        Frame::Write { id, uuid, value } => Request {
+            body: Some(value),
+            ..Request::new(RequestType::Write, id, uuid)
+        },
+        Frame::WriteTtl {
+            id,
+            uuid,
+            ttl,
+            value,
+        } => Request {
+            ttl: Some(ttl),
+            body: Some(value),
+            ..Request::new(RequestType::Write, id, uuid)
+        },
+        Frame::WriteVersion {
+            id,
+            uuid,
+            expected_version,
+            value,
+        } => Request {
+            expected_version: Some(expected_version),
+            body: Some(value),
+            ..Request::new(RequestType::Write, id, uuid)
+        },
+        Frame::Append { id, uuid, value } => Request {
+            body: Some(value),
+            ..Request::new(RequestType::Append, id, uuid)
+        },
+        Frame::Capabilities { id, flags } => Request {
+            flags,
+            ..Request::new(RequestType::Capabilities, id, [0; 16])
+        },
+        Frame::Scan {
+            id,
+            start,
+            end,
+            prefix_len,
+            limit,
+        } => {
+            // With a prefix the range covers every uuid starting with the
+            // first prefix_len bytes of start.
+            let prefix_len = cmp::min(prefix_len as usize, 16);
+            let end = if prefix_len > 0 {
+                prefix_end(&start[..prefix_len])
+            } else {
+                Some(end)
+            };
+            Request {
+                scan: Some(Scan { end, limit }),
+                ..Request::new(RequestType::Scan, id, start)
             }
+        }
+        Frame::Admin { id, command } => Request {
+            body: Some(command),
+            ..Request::new(RequestType::Admin, id, [0; 16])
+        },
+        Frame::Ping { id } => Request::new(RequestType::Ping, id, [0; 16]),
+    }
+}
 
-            b'R' => {
-                let mut buf = buf.split_to(1 + 16 + 4).into_buf();
-                buf.advance(1);
-
-                let mut uuid: [u8; 16] = [0; 16];
-                buf.copy_to_slice(&mut uuid);
-                let id = buf.get_u32_be();
-
-                self.len = None;
-                Ok(Some(Request {
-                    reqtype: RequestType::Read,
-                    id: id,
-                    uuid: uuid,
-                    body: None,
-                }))
-            }
+// Smallest uuid that doesn't start with `prefix`, or None if every uuid
+// after the prefix does.
+fn prefix_end(prefix: &[u8]) -> Option<[u8; 16]> {
+    let mut end = [0; 16];
+    end[..prefix.len()].copy_from_slice(prefix);
+    for i in (0..prefix.len()).rev() {
+        if end[i] < 0xFF {
+            end[i] += 1;
+            return Some(end);
+        }
+        end[i] = 0;
+    }
+    None
+}
 
-            any => {
-                panic!("got unexpected request type: {}", any);
-            }
+/// Body of a scan response: a u32 entry count, each entry as uuid, u32
+/// length and value, then a byte telling whether there is more and, if
+/// so, the uuid to start the next scan from.
+pub fn encode_scan(entries: &[([u8; 16], Bytes)], next: Option<[u8; 16]>) -> Bytes {
+    let len = entries.iter().map(|(_, v)| 16 + 4 + v.len()).sum::<usize>();
+    let mut body = BytesMut::with_capacity(4 + len + 1 + 16);
+    body.put_u32_be(entries.len() as u32);
+    for (uuid, value) in entries {
+        body.put_slice(uuid);
+        body.put_u32_be(value.len() as u32);
+        body.put_slice(value);
+    }
+    match next {
+        Some(uuid) => {
+            body.put_u8(1);
+            body.put_slice(&uuid);
         }
+        None => body.put_u8(0),
     }
+    body.freeze()
 }
 
 impl Encoder for Protocol {
@@ -99,27 +206,31 @@ impl Encoder for Protocol {
     type Error = io::Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        dst.reserve(18 + item.body.len());
-        dst.put_u32_be(item.id);
-        dst.put_u16_be(item.body.len() as u16);
-        dst.put_slice(&item.body);
+        item.encode(dst);
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use bytes::{Buf, BufMut, BytesMut, IntoBuf};
+    use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
     use tokio::codec::{Decoder, Encoder};
 
-    use super::{Protocol, RequestType, Response};
+    use protostore_client::Frame;
+
+    use crate::toc::hash_key;
+
+    use super::{
+        encode_scan, Protocol, RequestType, Response, Scan, Status, CAP_COMPRESSED, EXTENDED_LEN,
+        STATUS_LEN,
+    };
 
     #[test]
     fn decode_write() {
         let mut buf = BytesMut::with_capacity(128);
         let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
         let reqid = 42;
-        let data = BytesMut::from(vec![0, 2, 4, 8]);
+        let data = Bytes::from(vec![0, 2, 4, 8]);
 
         buf.put_u32_be(1 + 16 + 4 + data.len() as u32);
         buf.put(b'W');
@@ -129,7 +240,7 @@ mod tests {
 
         assert_eq!(4 + 1 + 16 + 4 + data.len(), buf.len());
 
-        let mut proto = Protocol { len: None };
+        let mut proto = Protocol::new();
         let decoded = proto.decode(&mut buf);
         assert!(decoded.is_ok());
         let request = decoded.unwrap().unwrap();
@@ -141,6 +252,78 @@ mod tests {
         assert_eq!(0, buf.len());
     }
 
+    #[test]
+    fn decode_write_ttl() {
+        let mut buf = BytesMut::with_capacity(128);
+        let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let reqid = 42;
+        let data = Bytes::from(vec![0, 2, 4, 8]);
+
+        buf.put_u32_be(1 + 16 + 4 + 4 + data.len() as u32);
+        buf.put(b'T');
+        buf.put_slice(&uuid);
+        buf.put_u32_be(reqid);
+        buf.put_u32_be(3600);
+        buf.put_slice(&data);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Write, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(uuid, request.uuid);
+        assert_eq!(Some(3600), request.ttl);
+        assert_eq!(Some(data), request.body);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_conditional_write() {
+        let mut buf = BytesMut::with_capacity(128);
+        let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let reqid = 42;
+        let data = Bytes::from(vec![0, 2, 4, 8]);
+
+        buf.put_u32_be(1 + 16 + 4 + 8 + data.len() as u32);
+        buf.put(b'V');
+        buf.put_slice(&uuid);
+        buf.put_u32_be(reqid);
+        buf.put_u64_be(7);
+        buf.put_slice(&data);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Write, request.reqtype);
+        assert_eq!(uuid, request.uuid);
+        assert_eq!(Some(7), request.expected_version);
+        assert_eq!(Some(data), request.body);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_append() {
+        let mut buf = BytesMut::with_capacity(128);
+        let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let reqid = 42;
+        let data = Bytes::from(vec![0, 2, 4, 8]);
+
+        buf.put_u32_be(1 + 16 + 4 + data.len() as u32);
+        buf.put(b'A');
+        buf.put_slice(&uuid);
+        buf.put_u32_be(reqid);
+        buf.put_slice(&data);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Append, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(uuid, request.uuid);
+        assert_eq!(Some(data), request.body);
+        assert_eq!(0, buf.len());
+    }
+
     #[test]
     fn decode_read() {
         let mut buf = BytesMut::with_capacity(128);
@@ -152,7 +335,7 @@ mod tests {
         buf.put_slice(&uuid);
         buf.put_u32_be(reqid);
 
-        let mut proto = Protocol { len: None };
+        let mut proto = Protocol::new();
         let decoded = proto.decode(&mut buf);
         assert!(decoded.is_ok());
         let request = decoded.unwrap().unwrap();
@@ -163,6 +346,180 @@ mod tests {
         assert_eq!(None, request.body);
     }
 
+    #[test]
+    fn decode_read_key() {
+        let mut buf = BytesMut::with_capacity(128);
+        let key = b"user:1234";
+        let reqid = 42;
+
+        buf.put_u32_be(1 + 4 + 2 + key.len() as u32);
+        buf.put(b'K');
+        buf.put_u32_be(reqid);
+        buf.put_u16_be(key.len() as u16);
+        buf.put_slice(key);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Read, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(hash_key(key), request.uuid);
+        assert_eq!(Some(Bytes::from(&key[..])), request.key);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn reject_bad_key_len() {
+        let key = b"user:1234";
+        for key_len in &[key.len() + 1, key.len() - 1] {
+            let mut buf = BytesMut::with_capacity(128);
+            buf.put_u32_be(1 + 4 + 2 + key.len() as u32);
+            buf.put(b'K');
+            buf.put_u32_be(42);
+            buf.put_u16_be(*key_len as u16);
+            buf.put_slice(key);
+
+            let mut proto = Protocol::new();
+            assert!(proto.decode(&mut buf).is_err());
+        }
+    }
+
+    #[test]
+    fn reject_short_frames() {
+        // The frame ends in the middle of its header
+        for kind in &[b'T', b'V', b'A'] {
+            let mut buf = BytesMut::with_capacity(128);
+            buf.put_u32_be(1 + 16);
+            buf.put(*kind);
+            buf.put_slice(&[0; 16]);
+
+            let mut proto = Protocol::new();
+            assert!(proto.decode(&mut buf).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_capabilities() {
+        let mut buf = BytesMut::with_capacity(128);
+        let reqid = 42;
+
+        buf.put_u32_be(1 + 4 + 4);
+        buf.put(b'C');
+        buf.put_u32_be(reqid);
+        buf.put_u32_be(CAP_COMPRESSED);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Capabilities, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(CAP_COMPRESSED, request.flags);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_ping() {
+        let mut buf = BytesMut::with_capacity(128);
+
+        buf.put_u32_be(1 + 4);
+        buf.put(b'P');
+        buf.put_u32_be(42);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Ping, request.reqtype);
+        assert_eq!(42, request.id);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_admin() {
+        let mut buf = BytesMut::with_capacity(128);
+        let reqid = 42;
+
+        buf.put_u32_be(1 + 4 + 7);
+        buf.put(b'M');
+        buf.put_u32_be(reqid);
+        buf.put_slice(b"latency");
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Admin, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(Some(Bytes::from(&b"latency"[..])), request.body);
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_scan() {
+        let mut buf = BytesMut::with_capacity(128);
+        let start = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let end = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9];
+        let reqid = 42;
+
+        buf.put_u32_be(1 + 4 + 16 + 16 + 1 + 4);
+        buf.put(b'S');
+        buf.put_u32_be(reqid);
+        buf.put_slice(&start);
+        buf.put_slice(&end);
+        buf.put_u8(0);
+        buf.put_u32_be(100);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(RequestType::Scan, request.reqtype);
+        assert_eq!(reqid, request.id);
+        assert_eq!(start, request.uuid);
+        assert_eq!(
+            Some(Scan {
+                end: Some(end),
+                limit: 100
+            }),
+            request.scan
+        );
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn decode_prefix_scan() {
+        let mut buf = BytesMut::with_capacity(128);
+        let start = [0xAB, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        buf.put_u32_be(1 + 4 + 16 + 16 + 1 + 4);
+        buf.put(b'S');
+        buf.put_u32_be(42);
+        buf.put_slice(&start);
+        buf.put_slice(&[0; 16]);
+        buf.put_u8(2);
+        buf.put_u32_be(0);
+
+        let mut proto = Protocol::new();
+        let request = proto.decode(&mut buf).unwrap().unwrap();
+
+        let end = [0xAC, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Some(end), request.scan.unwrap().end);
+    }
+
+    #[test]
+    fn encode_scan_body() {
+        let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let next = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+        let mut body =
+            BytesMut::from(&encode_scan(&[(uuid, Bytes::from(&b"abc"[..]))], Some(next))[..]);
+
+        assert_eq!(1, body.split_to(4).into_buf().get_u32_be());
+        assert_eq!(&uuid[..], &body.split_to(16)[..]);
+        assert_eq!(3, body.split_to(4).into_buf().get_u32_be());
+        assert_eq!(&b"abc"[..], &body.split_to(3)[..]);
+        assert_eq!(1, body.split_to(1).into_buf().get_u8());
+        assert_eq!(&next[..], &body.split_to(16)[..]);
+        assert_eq!(0, body.len());
+    }
+
     #[test]
     fn encode() {
         //let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
@@ -172,10 +529,11 @@ mod tests {
 
         let response = Response {
             id: reqid,
+            status: Status::Ok,
             body: body.freeze(),
         };
 
-        let mut proto = Protocol { len: None };
+        let mut proto = Protocol::new();
         let mut encoded = BytesMut::with_capacity(128);
         let result = proto.encode(response, &mut encoded);
         assert!(result.is_ok());
@@ -186,4 +544,164 @@ mod tests {
         assert_eq!(45, encoded.split_to(4).into_buf().get_u32_be());
         assert_eq!(0, encoded.len());
     }
+
+    #[test]
+    fn encode_extended_len() {
+        let reqid = 42;
+        let body = vec![7; 70_000];
+
+        let response = Response {
+            id: reqid,
+            status: Status::Ok,
+            body: body.into(),
+        };
+
+        let mut proto = Protocol::new();
+        let mut encoded = BytesMut::with_capacity(128);
+        let result = proto.encode(response, &mut encoded);
+        assert!(result.is_ok());
+        assert_eq!(4 + 2 + 4 + 70_000, encoded.len());
+
+        assert_eq!(42, encoded.split_to(4).into_buf().get_u32_be());
+        assert_eq!(EXTENDED_LEN, encoded.split_to(2).into_buf().get_u16_be());
+        assert_eq!(70_000, encoded.split_to(4).into_buf().get_u32_be());
+        assert_eq!(70_000, encoded.len());
+    }
+
+    #[test]
+    fn encode_status() {
+        let response = Response {
+            id: 42,
+            status: Status::Corrupt,
+            body: Bytes::new(),
+        };
+
+        let mut proto = Protocol::new();
+        let mut encoded = BytesMut::with_capacity(128);
+        let result = proto.encode(response, &mut encoded);
+        assert!(result.is_ok());
+        assert_eq!(7, encoded.len());
+
+        assert_eq!(42, encoded.split_to(4).into_buf().get_u32_be());
+        assert_eq!(STATUS_LEN, encoded.split_to(2).into_buf().get_u16_be());
+        assert_eq!(
+            Status::Corrupt.code(),
+            encoded.split_to(1).into_buf().get_u8()
+        );
+    }
+
+    #[test]
+    fn decode_client_frames() {
+        let uuid = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let value = Bytes::from(&b"abc"[..]);
+        let frames = vec![
+            (Frame::Read { id: 1, uuid }, RequestType::Read),
+            (
+                Frame::ReadKey {
+                    id: 2,
+                    key: value.clone(),
+                },
+                RequestType::Read,
+            ),
+            (
+                Frame::Write {
+                    id: 3,
+                    uuid,
+                    value: value.clone(),
+                },
+                RequestType::Write,
+            ),
+            (
+                Frame::WriteTtl {
+                    id: 4,
+                    uuid,
+                    ttl: 0,
+                    value: value.clone(),
+                },
+                RequestType::Write,
+            ),
+            (
+                Frame::WriteVersion {
+                    id: 5,
+                    uuid,
+                    expected_version: 9,
+                    value: value.clone(),
+                },
+                RequestType::Write,
+            ),
+            (
+                Frame::Append {
+                    id: 6,
+                    uuid,
+                    value: value.clone(),
+                },
+                RequestType::Append,
+            ),
+            (
+                Frame::Capabilities {
+                    id: 7,
+                    flags: CAP_COMPRESSED,
+                },
+                RequestType::Capabilities,
+            ),
+            (
+                Frame::Scan {
+                    id: 8,
+                    start: uuid,
+                    end: [0xFF; 16],
+                    prefix_len: 0,
+                    limit: 10,
+                },
+                RequestType::Scan,
+            ),
+            (
+                Frame::Admin {
+                    id: 9,
+                    command: value.clone(),
+                },
+                RequestType::Admin,
+            ),
+            (Frame::Ping { id: 10 }, RequestType::Ping),
+        ];
+
+        let mut buf = BytesMut::new();
+        for (frame, _) in frames.iter() {
+            frame.encode(&mut buf);
+        }
+
+        let mut proto = Protocol::new();
+        for (frame, reqtype) in frames {
+            let request = proto.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(reqtype, request.reqtype);
+            assert_eq!(frame.id(), request.id);
+            match frame {
+                Frame::ReadKey { key, .. } => assert_eq!(&key[..], &request.key.unwrap()[..]),
+                Frame::Write { .. } | Frame::Append { .. } | Frame::Admin { .. } => {
+                    assert_eq!(&value[..], &request.body.unwrap()[..])
+                }
+                Frame::WriteTtl { ttl, .. } => assert_eq!(Some(ttl), request.ttl),
+                Frame::WriteVersion {
+                    expected_version, ..
+                } => {
+                    assert_eq!(Some(expected_version), request.expected_version)
+                }
+                Frame::Capabilities { flags, .. } => assert_eq!(flags, request.flags),
+                Frame::Scan { limit, .. } => assert_eq!(limit, request.scan.unwrap().limit),
+                _ => (),
+            }
+        }
+        assert_eq!(0, buf.len());
+
+        // And the server's responses decode on the client
+        let response = Response {
+            id: 11,
+            status: Status::ReadOnly,
+            body: Bytes::new(),
+        };
+        let mut encoded = BytesMut::new();
+        proto.encode(response, &mut encoded).unwrap();
+        let decoded = Response::decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(Status::ReadOnly, decoded.status);
+        assert_eq!(11, decoded.id);
+    }
 }
@@ -1,36 +1,91 @@
 use log::{error, trace};
 use std::cmp;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use tokio::codec::Framed;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tracing::{info_span, Span};
 
-use bytes::Bytes;
+use bytes::{BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
+use zstd::block::Decompressor;
 
-use crate::protocol::{Protocol, Request, RequestType, Response};
-use crate::toc::TableOfContents;
+use crate::admin::Admin;
+use crate::backend::{DataIo, ReadBackend};
+use crate::cache::ValueCache;
+use crate::dataset::{Dataset, Datasets};
+use crate::latency::{self, Stage};
+use crate::metrics::metrics;
+use crate::protocol::{
+    encode_scan, Protocol, Request, RequestType, Response, Status, CAP_COMPRESSED, CAP_VERSIONS,
+};
+use crate::spans::{instrument, RequestTrace};
+use crate::toc::{Record, FLAG_KEYED};
+
+// Times an append is retried when it races with other writes to the
+// same value
+const MAX_APPEND_ATTEMPTS: usize = 8;
+
+// Record lengths are u32, appends can't grow a value past this
+const MAX_VALUE_LEN: u64 = u32::max_value() as u64;
+
+// Bounds on a single page of scan results
+const MAX_SCAN_ENTRIES: usize = 10_000;
+const MAX_SCAN_BYTES: u64 = 4 * 1024 * 1024;
+
+// What became of a value read from disk on its way to the client
+enum Value {
+    Found(Bytes),
+    // Stored under a different key that hashes to the same uuid
+    Missing,
+    Corrupt,
+}
+
+/// Settings shared by every connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConfig {
+    pub short_circuit_reads: bool,
+    // Fraction of requests that get a tracing span
+    pub trace_sample_rate: f64,
+    // Requests that take this long or more are logged in full
+    pub slow_request: Option<Duration>,
+}
 
 pub struct ProtostoreServer {
-    toc: Arc<TableOfContents>,
+    datasets: Arc<Datasets>,
+    cache: Arc<ValueCache>,
+    io: DataIo,
+    admin: Arc<Admin>,
     client: Framed<TcpStream, Protocol>,
-    max_value_len: usize,
-    short_circuit_reads: bool,
+    config: ServerConfig,
+    span: Span,
+    capabilities: u32,
+    // Built lazily from the dictionary of the generation it belongs to
+    decompressor: Option<(u64, Decompressor)>,
 }
 
 impl ProtostoreServer {
     pub fn new(
         socket: TcpStream,
-        toc: Arc<TableOfContents>,
-        max_value_len: usize,
-        short_circuit_reads: bool,
+        datasets: Arc<Datasets>,
+        cache: Arc<ValueCache>,
+        io: DataIo,
+        admin: Arc<Admin>,
+        config: ServerConfig,
     ) -> Self {
+        let span = info_span!("connection", peer = ?socket.peer_addr().ok());
         let client = Framed::new(socket, Protocol::new());
         ProtostoreServer {
-            toc,
+            datasets,
+            cache,
+            io,
+            admin,
             client,
-            max_value_len,
-            short_circuit_reads,
+            config,
+            span,
+            capabilities: 0,
+            decompressor: None,
         }
     }
 
@@ -39,62 +94,857 @@ impl ProtostoreServer {
             libc::pthread_self()
         });
 
+        metrics().connection_opened();
+        let span = self.span.clone();
+        let result = instrument(self.serve_requests(), span).await;
+        metrics().connection_closed();
+        result
+    }
+
+    async fn serve_requests(&mut self) -> Result<(), std::io::Error> {
         // In a loop, read data from the socket and write the data back.
         while let Some(request) = self.client.next().await {
-            let response = match request {
-                Ok(ref req) => match req.reqtype {
-                    RequestType::Read => self.respond_read(req).await?,
-                    RequestType::Write => self.respond_write(req).await?,
-                },
+            let req = match request {
+                Ok(req) => req,
                 Err(e) => {
                     error!("failed to read from client; err = {:?}", e);
                     return Err(e);
                 }
             };
+            let mut trace = RequestTrace::new(
+                &self.span,
+                &req,
+                self.config.trace_sample_rate,
+                self.config.slow_request,
+            );
+            let span = trace.span();
+            let response = instrument(self.respond(&req, &mut trace), span).await?;
+            trace.stage("respond");
+            let (status, body_len) = (response.status, response.body.len());
+            metrics().request(&req.reqtype, status, body_len);
             trace!("Responding {:?}", response);
+            let start = Instant::now();
             match self.client.send(response).await {
-                Ok(_) => (),
+                Ok(_) => {
+                    if req.reqtype == RequestType::Read {
+                        latency::record(Stage::Flush, start.elapsed());
+                    }
+                }
                 // Error sending disconnects
                 Err(e) => {
                     error!("failed to send to client; err = {:?}", e);
                     return Err(e);
                 }
             }
+            trace.stage("flush");
+            trace.finish(status, body_len);
         }
         Ok(())
     }
 
-    async fn respond_read(&self, req: &Request) -> Result<Response, std::io::Error> {
-        if self.short_circuit_reads {
+    async fn respond(
+        &mut self,
+        req: &Request,
+        trace: &mut RequestTrace,
+    ) -> Result<Response, std::io::Error> {
+        match req.reqtype {
+            RequestType::Read => self.respond_read(req, trace).await,
+            RequestType::Write => self.respond_write(req).await,
+            RequestType::Capabilities => self.respond_capabilities(req).await,
+            RequestType::Scan => self.respond_scan(req).await,
+            RequestType::Append => self.respond_append(req).await,
+            RequestType::Admin => self.respond_admin(req).await,
+            RequestType::Ping => Ok(Response {
+                id: req.id,
+                status: Status::Ok,
+                body: Bytes::new(),
+            }),
+        }
+    }
+
+    async fn respond_read(
+        &mut self,
+        req: &Request,
+        trace: &mut RequestTrace,
+    ) -> Result<Response, std::io::Error> {
+        if self.config.short_circuit_reads {
             return Ok(Response {
                 id: req.id,
+                status: Status::Ok,
                 body: Bytes::from(vec![0, 1, 2, 3]),
             });
         }
+        // The generation is held until the response is built, so a
+        // reload can't close the data file under this read.
+        let dataset = self.datasets.current();
         trace!("Searching for: {:?}", req);
-        let offset_and_len = self.toc.offset_and_len(&req.uuid);
-        trace!("Offset and len: {:?}", offset_and_len);
-        if let Some((offset, len)) = offset_and_len {
-            let aligned_offset = offset - (offset % 512);
-            let pad_left = offset - aligned_offset;
-            let padded = pad_left + len as u64;
-            let aligned_len = cmp::max(512, padded + 512 - (padded as u64 % 512));
-            Ok(Response {
-                id: req.id,
-                body: Bytes::from(&b"read"[..]),
-            })
+        let now = unix_now();
+        let start = Instant::now();
+        let record = dataset
+            .lookup(&req.uuid)
+            .filter(|record| !record.is_expired(now));
+        latency::record(Stage::Lookup, start.elapsed());
+        trace.stage("lookup");
+        trace!("Record: {:?}", record);
+        metrics().toc_lookup(record.is_some());
+        if let Some(record) = record {
+            trace.record_value(record.offset, record.len);
+            // Values too large for the cache don't touch it at all
+            let cacheable = self.cache.admits(record.len as usize);
+            let cached = if cacheable {
+                self.cache.get(&req.uuid, dataset.generation, record.offset)
+            } else {
+                None
+            };
+            let (value, fresh) = match cached {
+                Some(value) => {
+                    trace.stage("cache");
+                    (value, false)
+                }
+                None => {
+                    if dataset.data.backend() == ReadBackend::Direct {
+                        trace.record_aio(self.io.aio_session());
+                    }
+                    let value = self
+                        .io
+                        .read(&dataset.data, record.offset, record.len)
+                        .await?;
+                    trace.stage("read");
+                    (value, true)
+                }
+            };
+            let key = req.key.as_ref().map(|k| &k[..]);
+            let decoded = self.decode_value(&dataset, &req.uuid, key, &record, value.clone());
+            // Only values that passed the checksum are cached, copied out
+            // of the read buffer so the cache only holds on to the value
+            let corrupt = match decoded {
+                Value::Corrupt => true,
+                _ => false,
+            };
+            if fresh && cacheable && !corrupt {
+                self.cache.insert(
+                    &req.uuid,
+                    dataset.generation,
+                    record.offset,
+                    Bytes::from(&value[..]),
+                );
+            }
+            match decoded {
+                Value::Found(value) if self.capabilities & CAP_VERSIONS != 0 => {
+                    let mut body = BytesMut::with_capacity(8 + value.len());
+                    body.put_u64_be(record.version);
+                    body.extend_from_slice(&value);
+                    Ok(Response {
+                        id: req.id,
+                        status: Status::Ok,
+                        body: body.freeze(),
+                    })
+                }
+                Value::Found(body) => Ok(Response {
+                    id: req.id,
+                    status: Status::Ok,
+                    body,
+                }),
+                Value::Missing => Ok(not_found(req)),
+                Value::Corrupt => Ok(corrupt(req)),
+            }
         } else {
-            Ok(Response {
-                id: req.id,
-                body: Bytes::new(),
-            })
+            Ok(not_found(req))
+        }
+    }
+
+    async fn respond_scan(&mut self, req: &Request) -> Result<Response, std::io::Error> {
+        let scan = req.scan.as_ref().expect("scan request without a range");
+        let dataset = self.datasets.current();
+        let limit = match scan.limit as usize {
+            0 => MAX_SCAN_ENTRIES,
+            limit => cmp::min(limit, MAX_SCAN_ENTRIES),
+        };
+        trace!("Scanning {:?} to {:?} limit {}", req.uuid, scan.end, limit);
+
+        // Pick the records for this page, the first one left out is
+        // where the client should continue from.
+        let mut selected = vec![];
+        let mut selected_bytes = 0;
+        let mut next = None;
+        let now = unix_now();
+        for (uuid, record) in dataset.scan(&req.uuid, scan.end.as_ref()) {
+            if record.is_expired(now) {
+                continue;
+            }
+            if selected.len() == limit
+                || (selected_bytes > 0 && selected_bytes + record.len as u64 > MAX_SCAN_BYTES)
+            {
+                next = Some(uuid);
+                break;
+            }
+            selected_bytes += record.len as u64;
+            selected.push((uuid, record));
+        }
+
+        // Records stored back to back are fetched with one large read
+        let mut entries = Vec::with_capacity(selected.len());
+        let mut run_start = 0;
+        while run_start < selected.len() {
+            let first = selected[run_start].1;
+            let mut run_end = run_start + 1;
+            let mut end = first.offset + first.len as u64;
+            while run_end < selected.len() && selected[run_end].1.offset == end {
+                end += selected[run_end].1.len as u64;
+                run_end += 1;
+            }
+
+            let run = self
+                .io
+                .read(&dataset.data, first.offset, (end - first.offset) as u32)
+                .await?;
+            for (uuid, record) in selected[run_start..run_end].iter() {
+                let start = (record.offset - first.offset) as usize;
+                let value = run.slice(start, start + record.len as usize);
+                match self.decode_value(&dataset, uuid, None, record, value) {
+                    Value::Found(value) => entries.push((*uuid, value)),
+                    Value::Missing => (),
+                    Value::Corrupt => return Ok(corrupt(req)),
+                }
+            }
+            run_start = run_end;
+        }
+
+        Ok(Response {
+            id: req.id,
+            status: Status::Ok,
+            body: encode_scan(&entries, next),
+        })
+    }
+
+    // Turns the bytes stored for a record into what is sent to the
+    // client: verifies the checksum and strips the key and compression.
+    fn decode_value(
+        &mut self,
+        dataset: &Dataset,
+        uuid: &[u8; 16],
+        key: Option<&[u8]>,
+        record: &Record,
+        mut value: Bytes,
+    ) -> Value {
+        if !checksum_matches(uuid, record, &value) {
+            return Value::Corrupt;
+        }
+
+        let mut flags = record.flags;
+        if record.is_keyed() {
+            value = match split_key(&value) {
+                // A different key that hashes to the same uuid
+                Some((stored_key, _)) if key.map_or(false, |k| k != &stored_key[..]) => {
+                    return Value::Missing;
+                }
+                Some((_, value)) => value,
+                None => {
+                    error!(
+                        "malformed key prefix for {:?} at offset {}",
+                        uuid, record.offset
+                    );
+                    return Value::Corrupt;
+                }
+            };
+            flags &= !FLAG_KEYED;
+        }
+
+        // Clients that handle compression get the stored bytes
+        // prefixed by the record flags so they know what they got.
+        if self.capabilities & CAP_COMPRESSED != 0 {
+            let mut body = BytesMut::with_capacity(1 + value.len());
+            body.put_u8(flags);
+            body.extend_from_slice(&value);
+            return Value::Found(body.freeze());
+        }
+
+        if record.is_compressed() {
+            value = match self.decompress(dataset, &value) {
+                Ok(value) => value,
+                Err(e) => {
+                    error!(
+                        "could not decompress {:?} at offset {}: {:?}",
+                        uuid, record.offset, e
+                    );
+                    return Value::Corrupt;
+                }
+            };
         }
+
+        Value::Found(value)
     }
 
-    async fn respond_write(&self, req: &Request) -> Result<Response, std::io::Error> {
+    fn decompress(&mut self, dataset: &Dataset, value: &[u8]) -> Result<Bytes, std::io::Error> {
+        if value.len() < 4 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "compressed value is too short",
+            ));
+        }
+        let raw_len = LittleEndian::read_u32(&value[..4]) as usize;
+
+        let stale = match self.decompressor {
+            Some((generation, _)) => generation != dataset.generation,
+            None => true,
+        };
+        if stale {
+            self.decompressor = dataset
+                .toc
+                .dictionary()
+                .map(|dict| (dataset.generation, Decompressor::with_dict(dict.to_vec())));
+        }
+
+        match self.decompressor {
+            Some((_, ref mut decompressor)) => {
+                Ok(decompressor.decompress(&value[4..], raw_len)?.into())
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no dictionary to decompress with",
+            )),
+        }
+    }
+
+    async fn respond_capabilities(&mut self, req: &Request) -> Result<Response, std::io::Error> {
+        self.capabilities = req.flags;
+
+        // Clients asking for compressed values need the dictionary to
+        // make sense of them.
+        let dataset = self.datasets.current();
+        let body = match dataset.toc.dictionary() {
+            Some(dict) if req.flags & CAP_COMPRESSED != 0 => Bytes::from(dict),
+            _ => Bytes::new(),
+        };
         Ok(Response {
             id: req.id,
-            body: Bytes::from(&b"write"[..]),
+            status: Status::Ok,
+            body,
         })
     }
+
+    async fn respond_admin(&mut self, req: &Request) -> Result<Response, std::io::Error> {
+        let command = req.body.as_ref().map_or(&[][..], |body| &body[..]);
+        let command = String::from_utf8_lossy(command);
+        let body = self.admin.run(command.trim(), &self.datasets);
+        Ok(Response {
+            id: req.id,
+            status: Status::Ok,
+            body: Bytes::from(body),
+        })
+    }
+
+    async fn respond_write(&mut self, req: &Request) -> Result<Response, std::io::Error> {
+        let value = req.body.as_ref().map_or(&[][..], |body| &body[..]);
+        // A reload seals the generation it replaces, writes that were
+        // committing to it go again on the new one.
+        let committed = loop {
+            let dataset = self.datasets.current();
+            if self.admin.read_only() || !dataset.overlay.writable() {
+                return Ok(read_only(req));
+            }
+            let now = unix_now();
+
+            // Fail early when the version is already known not to match, the
+            // check that counts is the one in commit.
+            if let Some(expected) = req.expected_version {
+                let current = dataset
+                    .lookup(&req.uuid)
+                    .filter(|record| !record.is_expired(now))
+                    .map_or(0, |record| record.version);
+                if current != expected {
+                    return Ok(conflict(req));
+                }
+            }
+
+            let expires_at = req.ttl.map_or(0, |ttl| now + ttl as u64);
+            let committed = self
+                .write_record(
+                    &dataset,
+                    &req.uuid,
+                    req.expected_version,
+                    value,
+                    0,
+                    expires_at,
+                )
+                .await;
+            match committed {
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                committed => break committed?,
+            }
+        };
+        match committed {
+            Ok(version) if req.expected_version.is_some() => {
+                let mut body = BytesMut::with_capacity(8);
+                body.put_u64_be(version);
+                Ok(Response {
+                    id: req.id,
+                    status: Status::Ok,
+                    body: body.freeze(),
+                })
+            }
+            Ok(_) => Ok(Response {
+                id: req.id,
+                status: Status::Ok,
+                body: Bytes::new(),
+            }),
+            Err(_) => Ok(conflict(req)),
+        }
+    }
+
+    // Appends are a read-modify-write of the whole value. A concurrent
+    // write in between makes the commit fail, and the append starts over
+    // from the new value. Every append reads and rewrites all of it, so
+    // growing a value in small appends costs IO and data file space
+    // quadratic in its size until the next compaction.
+    async fn respond_append(&mut self, req: &Request) -> Result<Response, std::io::Error> {
+        let suffix = req.body.as_ref().map_or(&[][..], |body| &body[..]);
+        for _ in 0..MAX_APPEND_ATTEMPTS {
+            let dataset = self.datasets.current();
+            if self.admin.read_only() || !dataset.overlay.writable() {
+                return Ok(read_only(req));
+            }
+            let now = unix_now();
+            let record = dataset
+                .lookup(&req.uuid)
+                .filter(|record| !record.is_expired(now));
+
+            let (mut value, flags, expires_at, version) = match record {
+                // Spares reading a value that can't take the suffix
+                Some(record)
+                    if !record.is_compressed()
+                        && record.len as u64 + suffix.len() as u64 > MAX_VALUE_LEN =>
+                {
+                    return Ok(too_large(req));
+                }
+                Some(record) => {
+                    let stored = self
+                        .io
+                        .read(&dataset.data, record.offset, record.len)
+                        .await?;
+                    match self.uncompressed_value(&dataset, &req.uuid, &record, stored) {
+                        Some(value) => (
+                            BytesMut::from(&value[..]),
+                            record.flags & FLAG_KEYED,
+                            record.expires_at,
+                            record.version,
+                        ),
+                        None => return Ok(corrupt(req)),
+                    }
+                }
+                None => (BytesMut::new(), 0, 0, 0),
+            };
+            if value.len() as u64 + suffix.len() as u64 > MAX_VALUE_LEN {
+                return Ok(too_large(req));
+            }
+            value.extend_from_slice(suffix);
+
+            let committed = self
+                .write_record(
+                    &dataset,
+                    &req.uuid,
+                    Some(version),
+                    &value,
+                    flags,
+                    expires_at,
+                )
+                .await;
+            let committed = match committed {
+                // The generation was replaced, start over on the new one
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                committed => committed?,
+            };
+            if committed.is_ok() {
+                return Ok(Response {
+                    id: req.id,
+                    status: Status::Ok,
+                    body: Bytes::new(),
+                });
+            }
+            trace!(
+                "Append to {:?} raced with another write, retrying",
+                req.uuid
+            );
+        }
+        Ok(conflict(req))
+    }
+
+    // The bytes stored for a record with compression undone, keeping
+    // the key prefix of keyed values. None if they are corrupt.
+    fn uncompressed_value(
+        &mut self,
+        dataset: &Dataset,
+        uuid: &[u8; 16],
+        record: &Record,
+        value: Bytes,
+    ) -> Option<Bytes> {
+        if !checksum_matches(uuid, record, &value) {
+            return None;
+        }
+        if !record.is_compressed() {
+            return Some(value);
+        }
+
+        let (prefix, payload) = if record.is_keyed() {
+            let (key, payload) = split_key(&value)?;
+            (value.slice_to(2 + key.len()), payload)
+        } else {
+            (Bytes::new(), value)
+        };
+        match self.decompress(dataset, &payload) {
+            Ok(payload) => {
+                let mut value = BytesMut::with_capacity(prefix.len() + payload.len());
+                value.extend_from_slice(&prefix);
+                value.extend_from_slice(&payload);
+                Some(value.freeze())
+            }
+            Err(e) => {
+                error!(
+                    "could not decompress {:?} at offset {}: {:?}",
+                    uuid, record.offset, e
+                );
+                None
+            }
+        }
+    }
+
+    // Appends `value` to the data file and commits a record for it if
+    // the current version is `expected`. Returns the new version, or
+    // the current one when it didn't match.
+    async fn write_record(
+        &self,
+        dataset: &Arc<Dataset>,
+        uuid: &[u8; 16],
+        expected: Option<u64>,
+        value: &[u8],
+        flags: u8,
+        expires_at: u64,
+    ) -> Result<Result<u64, u64>, std::io::Error> {
+        // Empty values still get a slot of their own
+        let slot = cmp::max(512, value.len() as u64);
+        let offset = dataset.overlay.reserve(slot);
+        let written = async {
+            self.io.write(&dataset.data, offset, value).await?;
+            self.io.sync(&dataset.data).await
+        }
+        .await;
+        if let Err(e) = written {
+            dataset.overlay.release(offset, slot);
+            return Err(e);
+        }
+
+        let record = Record {
+            offset,
+            len: value.len() as u32,
+            checksum: Some(crc32c::crc32c(value)),
+            flags,
+            expires_at,
+            version: 0,
+        };
+        trace!("Committing {:?} for {:?}", record, uuid);
+        let base = dataset.toc.lookup(uuid);
+        // A failed commit may have left the entry in the log, so the slot
+        // is only handed back when the version didn't match
+        let committed = {
+            let dataset = dataset.clone();
+            let uuid = *uuid;
+            self.io
+                .blocking(move || {
+                    dataset
+                        .overlay
+                        .commit(&uuid, base, expected, record, unix_now())
+                })
+                .await?
+        };
+        match committed {
+            Ok(_) => self.cache.remove(uuid),
+            Err(_) => dataset.overlay.release(offset, slot),
+        }
+        Ok(committed)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time drift!")
+        .as_secs()
+}
+
+fn not_found(req: &Request) -> Response {
+    Response {
+        id: req.id,
+        status: Status::Ok,
+        body: Bytes::new(),
+    }
+}
+
+fn conflict(req: &Request) -> Response {
+    Response {
+        id: req.id,
+        status: Status::Conflict,
+        body: Bytes::new(),
+    }
+}
+
+fn read_only(req: &Request) -> Response {
+    Response {
+        id: req.id,
+        status: Status::ReadOnly,
+        body: Bytes::new(),
+    }
+}
+
+fn too_large(req: &Request) -> Response {
+    Response {
+        id: req.id,
+        status: Status::TooLarge,
+        body: Bytes::new(),
+    }
+}
+
+fn corrupt(req: &Request) -> Response {
+    metrics().corrupt_value();
+    Response {
+        id: req.id,
+        status: Status::Corrupt,
+        body: Bytes::new(),
+    }
+}
+
+fn checksum_matches(uuid: &[u8; 16], record: &Record, value: &[u8]) -> bool {
+    match record.checksum {
+        Some(expected) => {
+            let actual = crc32c::crc32c(value);
+            if actual != expected {
+                error!(
+                    "checksum mismatch for {:?} at offset {}: expected {:x}, got {:x}",
+                    uuid, record.offset, expected, actual
+                );
+            }
+            actual == expected
+        }
+        None => true,
+    }
+}
+
+// Splits a keyed value into the key it was stored under and the rest.
+fn split_key(value: &Bytes) -> Option<(Bytes, Bytes)> {
+    if value.len() < 2 {
+        return None;
+    }
+    let key_len = LittleEndian::read_u16(&value[..2]) as usize;
+    if value.len() < 2 + key_len {
+        return None;
+    }
+    Some((value.slice(2, 2 + key_len), value.slice_from(2 + key_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::{mpsc, Arc};
+
+    use bytes::{ByteOrder, Bytes, LittleEndian};
+    use futures::join;
+    use rayon::{ThreadPool, ThreadPoolBuilder};
+    use tempdir::TempDir;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::{ProtostoreServer, ServerConfig};
+    use crate::admin::Admin;
+    use crate::aio::Session;
+    use crate::backend::{DataIo, ReadBackend};
+    use crate::cache::ValueCache;
+    use crate::dataset::Datasets;
+    use crate::placement::{Layout, Slot};
+    use crate::protocol::{Request, RequestType, Status};
+    use crate::toc::tests::write_toc;
+    use crate::toc::{Record, FLAG_COMPRESSED, FLAG_KEYED};
+
+    const KEY: &[u8] = b"user:1";
+
+    // Plain values at [1; 16] and [2; 16], and a keyed, compressed one
+    // at [3; 16]. All of them hold "abcd" or "efgh".
+    fn write_dataset(path: &Path) {
+        let dict = b"abcdefgh".repeat(8);
+        let mut compressor = zstd::block::Compressor::with_dict(dict.clone());
+        let mut keyed = vec![0; 2];
+        LittleEndian::write_u16(&mut keyed, KEY.len() as u16);
+        keyed.extend_from_slice(KEY);
+        keyed.extend_from_slice(&[4, 0, 0, 0]);
+        keyed.extend_from_slice(&compressor.compress(b"abcd", 3).unwrap());
+
+        let mut data = b"abcdefgh".to_vec();
+        data.extend_from_slice(&keyed);
+        write_toc(
+            path,
+            &[[1; 16], [2; 16], [3; 16]],
+            &[0, 4, 8],
+            &[4, 4, keyed.len() as u32],
+            4,
+        );
+        fs::write(path.join("protostore.data"), data).unwrap();
+        fs::write(
+            path.join("protostore.toc.flags"),
+            [0, 0, FLAG_KEYED | FLAG_COMPRESSED],
+        )
+        .unwrap();
+        fs::write(path.join("protostore.dict"), dict).unwrap();
+    }
+
+    // A server for one end of a local connection, the client end is
+    // returned so it stays open
+    fn server(rt: &mut Runtime, path: &Path, pool: ThreadPool) -> (ProtostoreServer, TcpStream) {
+        let datasets = Arc::new(Datasets::open(path, ReadBackend::Buffered, true).unwrap());
+        let (aio, _) = Session::local(4).unwrap();
+        let io = DataIo::new(aio, Arc::new(pool));
+        let layout = Layout {
+            main: Slot { pu: 0, node: 0 },
+            aio: vec![],
+            tcp: vec![],
+        };
+        let admin = Arc::new(Admin::new(PathBuf::from(path), layout, None));
+        let config = ServerConfig {
+            short_circuit_reads: false,
+            trace_sample_rate: 0.0,
+            slow_request: None,
+        };
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(&addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted, client) =
+            rt.block_on(async { join!(listener.accept(), TcpStream::connect(&addr)) });
+        let (socket, _) = accepted.unwrap();
+        let server = ProtostoreServer::new(
+            socket,
+            datasets,
+            Arc::new(ValueCache::new(0)),
+            io,
+            admin,
+            config,
+        );
+        (server, client.unwrap())
+    }
+
+    fn pool() -> ThreadPool {
+        ThreadPoolBuilder::new().num_threads(1).build().unwrap()
+    }
+
+    fn append(uuid: [u8; 16], suffix: &[u8]) -> Request {
+        Request {
+            body: Some(Bytes::from(suffix)),
+            ..Request::new(RequestType::Append, 42, uuid)
+        }
+    }
+
+    // The current record of `uuid` and the bytes stored for it
+    fn stored(rt: &mut Runtime, server: &ProtostoreServer, uuid: &[u8; 16]) -> (Record, Bytes) {
+        let dataset = server.datasets.current();
+        let record = dataset.lookup(uuid).unwrap();
+        let value = rt
+            .block_on(server.io.read(&dataset.data, record.offset, record.len))
+            .unwrap();
+        (record, value)
+    }
+
+    #[test]
+    fn append_to_missing() {
+        let dir = TempDir::new("server").unwrap();
+        write_dataset(dir.path());
+        let mut rt = Runtime::new().unwrap();
+        let (mut server, _client) = server(&mut rt, dir.path(), pool());
+
+        let response = rt
+            .block_on(server.respond_append(&append([9; 16], b"xy")))
+            .unwrap();
+        assert_eq!(Status::Ok, response.status);
+
+        let (record, value) = stored(&mut rt, &server, &[9; 16]);
+        assert_eq!(1, record.version);
+        assert_eq!(0, record.flags);
+        assert_eq!(&b"xy"[..], &value[..]);
+    }
+
+    #[test]
+    fn append_keyed_compressed() {
+        let dir = TempDir::new("server").unwrap();
+        write_dataset(dir.path());
+        let mut rt = Runtime::new().unwrap();
+        let (mut server, _client) = server(&mut rt, dir.path(), pool());
+
+        let response = rt
+            .block_on(server.respond_append(&append([3; 16], b"xy")))
+            .unwrap();
+        assert_eq!(Status::Ok, response.status);
+
+        // The key prefix stays, the value is stored uncompressed
+        let (record, value) = stored(&mut rt, &server, &[3; 16]);
+        assert_eq!(2, record.version);
+        assert_eq!(FLAG_KEYED, record.flags);
+        assert_eq!(KEY.len() as u16, LittleEndian::read_u16(&value[..2]));
+        assert_eq!(KEY, &value[2..2 + KEY.len()]);
+        assert_eq!(&b"abcdxy"[..], &value[2 + KEY.len()..]);
+    }
+
+    #[test]
+    fn append_retries_lost_race() {
+        let dir = TempDir::new("server").unwrap();
+        write_dataset(dir.path());
+        let mut rt = Runtime::new().unwrap();
+
+        // The only blocking thread waits until the other write is in, so
+        // the append has looked up the record but not read it yet
+        let pool = pool();
+        let (unblock_tx, unblock_rx) = mpsc::channel();
+        pool.spawn(move || unblock_rx.recv().unwrap());
+        let (mut server, _client) = server(&mut rt, dir.path(), pool);
+
+        let dataset = server.datasets.current();
+        let other = async {
+            let base = dataset.toc.lookup(&[1; 16]);
+            let record = dataset.toc.lookup(&[2; 16]).unwrap();
+            dataset
+                .overlay
+                .commit(&[1; 16], base, None, record, 0)
+                .unwrap()
+                .unwrap();
+            unblock_tx.send(()).unwrap();
+        };
+        let req = append([1; 16], b"xy");
+        let (response, ()) = rt.block_on(async { join!(server.respond_append(&req), other) });
+        assert_eq!(Status::Ok, response.unwrap().status);
+
+        // Appended to the value of the write it lost to
+        let (record, value) = stored(&mut rt, &server, &[1; 16]);
+        assert_eq!(3, record.version);
+        assert_eq!(&b"efghxy"[..], &value[..]);
+    }
+
+    #[test]
+    fn append_too_large() {
+        let dir = TempDir::new("server").unwrap();
+        let len = u32::max_value() - 1;
+        write_toc(dir.path(), &[[1; 16]], &[0], &[len], 4);
+        // Sparse, nothing is read before the append is turned down
+        File::create(dir.path().join("protostore.data"))
+            .unwrap()
+            .set_len(len as u64)
+            .unwrap();
+        let mut rt = Runtime::new().unwrap();
+        let (mut server, _client) = server(&mut rt, dir.path(), pool());
+
+        let response = rt
+            .block_on(server.respond_append(&append([1; 16], b"xy")))
+            .unwrap();
+        assert_eq!(Status::TooLarge, response.status);
+        assert_eq!(
+            1,
+            server.datasets.current().lookup(&[1; 16]).unwrap().version
+        );
+    }
 }
@@ -0,0 +1,222 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use tracing::{debug, field, info_span, Span};
+
+use crate::protocol::{Request, RequestType, Status};
+
+/// Details and timings of one request. Sampled requests get a span
+/// that details are recorded on as they become known. Requests slower
+/// than the threshold are logged in full whether sampled or not. With
+/// neither, nothing is kept.
+pub struct RequestTrace {
+    traced: Option<Box<Traced>>,
+}
+
+struct Traced {
+    span: Span,
+    sampled: bool,
+    slow: Option<Duration>,
+    started: Instant,
+    // End of the last stage
+    mark: Instant,
+    kind: RequestType,
+    id: u32,
+    uuid: [u8; 16],
+    offset: Option<u64>,
+    len: Option<u32>,
+    aio: Option<usize>,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl RequestTrace {
+    pub fn new(
+        connection: &Span,
+        req: &Request,
+        sample_rate: f64,
+        slow: Option<Duration>,
+    ) -> RequestTrace {
+        let sampled = sample_rate > 0.0 && rand::random::<f64>() < sample_rate;
+        if !sampled && slow.is_none() {
+            return RequestTrace { traced: None };
+        }
+
+        let span = if sampled {
+            info_span!(
+                parent: connection,
+                "request",
+                kind = ?req.reqtype,
+                id = req.id,
+                uuid = %hex(&req.uuid),
+                offset = field::Empty,
+                len = field::Empty,
+                aio = field::Empty,
+                status = field::Empty,
+                elapsed_us = field::Empty
+            )
+        } else {
+            Span::none()
+        };
+        let now = Instant::now();
+        RequestTrace {
+            traced: Some(Box::new(Traced {
+                span,
+                sampled,
+                slow,
+                started: now,
+                mark: now,
+                kind: req.reqtype,
+                id: req.id,
+                uuid: req.uuid,
+                offset: None,
+                len: None,
+                aio: None,
+                stages: vec![],
+            })),
+        }
+    }
+
+    /// The span of the request, to enter while handling it.
+    pub fn span(&self) -> Span {
+        self.traced
+            .as_ref()
+            .map_or_else(Span::none, |traced| traced.span.clone())
+    }
+
+    /// Where the value of the request is stored.
+    pub fn record_value(&mut self, offset: u64, len: u32) {
+        if let Some(traced) = self.traced.as_mut() {
+            traced.span.record("offset", &offset);
+            traced.span.record("len", &len);
+            traced.offset = Some(offset);
+            traced.len = Some(len);
+        }
+    }
+
+    /// The AIO session that read the value.
+    pub fn record_aio(&mut self, session: usize) {
+        if let Some(traced) = self.traced.as_mut() {
+            traced.span.record("aio", &session);
+            traced.aio = Some(session);
+        }
+    }
+
+    /// Ends `stage`, which started when the previous one ended.
+    pub fn stage(&mut self, stage: &'static str) {
+        if let Some(traced) = self.traced.as_mut() {
+            let now = Instant::now();
+            let elapsed = now - traced.mark;
+            traced.mark = now;
+            if traced.sampled {
+                debug!(parent: &traced.span, stage = stage, elapsed_us = elapsed.as_micros() as u64);
+            }
+            traced.stages.push((stage, elapsed));
+        }
+    }
+
+    /// Closes the span, and logs the request if it was slow.
+    pub fn finish(self, status: Status, body_len: usize) {
+        let traced = match self.traced {
+            Some(traced) => traced,
+            None => return,
+        };
+        let elapsed = traced.started.elapsed();
+        traced.span.record("status", &field::debug(status));
+        traced
+            .span
+            .record("elapsed_us", &(elapsed.as_micros() as u64));
+        if traced.slow.map_or(false, |slow| elapsed >= slow) {
+            warn!("{}", traced.describe(status, body_len, elapsed));
+        }
+    }
+}
+
+impl Traced {
+    fn describe(&self, status: Status, body_len: usize, elapsed: Duration) -> String {
+        let mut out = format!(
+            "slow request {:?} id:{} uuid:{}",
+            self.kind,
+            self.id,
+            hex(&self.uuid)
+        );
+        if let (Some(offset), Some(len)) = (self.offset, self.len) {
+            write!(out, " offset:{} len:{}", offset, len).unwrap();
+        }
+        if let Some(aio) = self.aio {
+            write!(out, " aio:{}", aio).unwrap();
+        }
+        write!(out, " status:{:?} body:{}", status, body_len).unwrap();
+        for (stage, elapsed) in self.stages.iter() {
+            write!(out, " {}:{}us", stage, elapsed.as_micros()).unwrap();
+        }
+        write!(out, " total:{}us", elapsed.as_micros()).unwrap();
+        out
+    }
+}
+
+/// Enters `span` every time `future` is polled, so what it logs is
+/// attached to the span. Holding an entered span across an await
+/// would leave it entered while other tasks on the thread run.
+pub fn instrument<F: Future>(future: F, span: Span) -> Instrumented<F> {
+    Instrumented { future, span }
+}
+
+pub struct Instrumented<F> {
+    future: F,
+    span: Span,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // The future is never moved out of the pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+        let _entered = this.span.enter();
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+    }
+}
+
+fn hex(uuid: &[u8; 16]) -> String {
+    uuid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracing::Span;
+
+    use super::RequestTrace;
+    use crate::protocol::{Request, RequestType, Status};
+
+    #[test]
+    fn describe() {
+        let req = Request::new(RequestType::Read, 7, [0xab; 16]);
+        let slow = Some(Duration::from_millis(1));
+        let mut trace = RequestTrace::new(&Span::none(), &req, 0.0, slow);
+        trace.record_value(4096, 100);
+        trace.record_aio(1);
+        trace.stage("lookup");
+
+        let traced = trace.traced.as_ref().unwrap();
+        let description = traced.describe(Status::Ok, 100, Duration::from_micros(1500));
+        assert!(description.starts_with(&format!(
+            "slow request Read id:7 uuid:{} offset:4096 len:100 aio:1 status:Ok body:100 lookup:",
+            "ab".repeat(16)
+        )));
+        assert!(description.ends_with(" total:1500us"));
+    }
+
+    #[test]
+    fn untraced() {
+        let req = Request::new(RequestType::Read, 7, [0xab; 16]);
+        let mut trace = RequestTrace::new(&Span::none(), &req, 0.0, None);
+        trace.stage("lookup");
+        assert!(trace.traced.is_none());
+    }
+}
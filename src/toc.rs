@@ -1,17 +1,78 @@
-use std::fs::File;
+use std::cmp;
+use std::fs::{self, File};
+use std::hash::Hasher;
 use std::io;
-use std::mem::transmute;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::slice::from_raw_parts;
 
+use bytes::{BigEndian, ByteOrder, LittleEndian};
 use memmap;
+use siphasher::sip128::{Hasher128, SipHasher13};
+
+/// The value is a u32 LE uncompressed length followed by a zstd frame
+/// compressed with the dataset dictionary.
+pub const FLAG_COMPRESSED: u8 = 1;
+
+/// The value starts with a u16 LE key length and the key it was stored
+/// under, so reads by key can tell hash collisions from hits. When
+/// combined with `FLAG_COMPRESSED` only what follows the key is
+/// compressed.
+pub const FLAG_KEYED: u8 = 2;
+
+/// Maps an arbitrary byte-string key into the uuid keyspace.
+pub fn hash_key(key: &[u8]) -> [u8; 16] {
+    let mut hasher = SipHasher13::new();
+    hasher.write(key);
+    let hash = hasher.finish128();
+
+    let mut uuid = [0; 16];
+    BigEndian::write_u64(&mut uuid[..8], hash.h1);
+    BigEndian::write_u64(&mut uuid[8..], hash.h2);
+    uuid
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub offset: u64,
+    pub len: u32,
+    // CRC32C of the value, if the dataset was built with checksums
+    pub checksum: Option<u32>,
+    pub flags: u8,
+    // Unix timestamp in seconds after which the record is gone, 0 if it
+    // never expires
+    pub expires_at: u64,
+    // Bumped on every write, records the dataset was built with are at
+    // version 1 unless compaction carried a written version over
+    pub version: u64,
+}
+
+impl Record {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    pub fn is_keyed(&self) -> bool {
+        self.flags & FLAG_KEYED != 0
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
 
 #[derive(Debug)]
 pub struct TableOfContents {
     uuids: Vec<[u8; 16]>,
     offsets: Vec<u64>,
-    lens: Vec<u16>,
-    _files: Vec<memmap::Mmap>,
+    lens: Vec<u32>,
+    checksums: Option<Vec<u32>>,
+    flags: Option<Vec<u8>>,
+    expiry: Option<Vec<u64>>,
+    versions: Option<Vec<u64>>,
+    dictionary: Option<Vec<u8>>,
+    // Found on open, scanning every length for it is too slow to do
+    // per request
+    max_len: u32,
 }
 
 impl TableOfContents {
@@ -19,10 +80,20 @@ impl TableOfContents {
         let mut uuids_path = PathBuf::from(path);
         let mut offsets_path = PathBuf::from(path);
         let mut lens_path = PathBuf::from(path);
+        let mut checksums_path = PathBuf::from(path);
+        let mut flags_path = PathBuf::from(path);
+        let mut expiry_path = PathBuf::from(path);
+        let mut versions_path = PathBuf::from(path);
+        let mut dictionary_path = PathBuf::from(path);
 
         uuids_path.push("protostore.toc.uuids");
         offsets_path.push("protostore.toc.offsets");
         lens_path.push("protostore.toc.lengths");
+        checksums_path.push("protostore.toc.checksums");
+        flags_path.push("protostore.toc.flags");
+        expiry_path.push("protostore.toc.expiry");
+        versions_path.push("protostore.toc.versions");
+        dictionary_path.push("protostore.dict");
 
         let uuids_meta = uuids_path.metadata()?;
         let num_entries = uuids_meta.len() / 16;
@@ -35,69 +106,316 @@ impl TableOfContents {
         let offsets_mmap = unsafe { memmap::Mmap::map(&offsets_file)? };
         let lens_mmap = unsafe { memmap::Mmap::map(&lens_file)? };
 
-        let uuids = unsafe {
-            let slice = &uuid_mmap[..];
-            let uuid: &[[u8; 16]] = transmute(slice);
-            from_raw_parts(uuid.as_ptr(), num_entries as usize).to_vec()
+        if uuids_meta.len() % 16 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "uuids file has {} bytes, not a multiple of 16",
+                    uuids_meta.len()
+                ),
+            ));
+        }
+        if offsets_mmap.len() as u64 != num_entries * 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "offsets file has {} bytes for {} entries",
+                    offsets_mmap.len(),
+                    num_entries
+                ),
+            ));
+        }
+
+        // Files are written little-endian by mk_data. Decoding them
+        // byte by byte makes the result independent of the host byte
+        // order and of how the mappings happen to be aligned.
+        let uuids = uuid_mmap
+            .chunks_exact(16)
+            .map(|chunk| {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(chunk);
+                uuid
+            })
+            .collect();
+
+        let offsets = offsets_mmap
+            .chunks_exact(8)
+            .map(LittleEndian::read_u64)
+            .collect();
+
+        // Older datasets store lengths as u16, newer ones as u32. The
+        // width is inferred from the size of the lengths file.
+        let lens = match lens_width(lens_mmap.len() as u64, num_entries)? {
+            2 => lens_mmap
+                .chunks_exact(2)
+                .map(|len| LittleEndian::read_u16(len) as u32)
+                .collect(),
+            _ => lens_mmap
+                .chunks_exact(4)
+                .map(LittleEndian::read_u32)
+                .collect(),
+        };
+
+        // Checksums are optional, datasets built before they existed
+        // are served without verification.
+        let checksums = if checksums_path.exists() {
+            let checksums_file = File::open(checksums_path)?;
+            let checksums_mmap = unsafe { memmap::Mmap::map(&checksums_file)? };
+            if checksums_mmap.len() as u64 != num_entries * 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "checksums file has {} bytes for {} entries",
+                        checksums_mmap.len(),
+                        num_entries
+                    ),
+                ));
+            }
+
+            Some(
+                checksums_mmap
+                    .chunks_exact(4)
+                    .map(LittleEndian::read_u32)
+                    .collect(),
+            )
+        } else {
+            None
         };
 
-        let offsets = unsafe {
-            let slice = &offsets_mmap[..];
-            let offsets: &[u64] = transmute(slice);
-            from_raw_parts(offsets.as_ptr(), num_entries as usize).to_vec()
+        // Flags and the compression dictionary only exist for datasets
+        // built with compression.
+        let flags = if flags_path.exists() {
+            let flags = fs::read(flags_path)?;
+            if flags.len() as u64 != num_entries {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "flags file has {} bytes for {} entries",
+                        flags.len(),
+                        num_entries
+                    ),
+                ));
+            }
+            Some(flags)
+        } else {
+            None
         };
 
-        let lens = unsafe {
-            let slice = &lens_mmap[..];
-            let lens: &[u16] = transmute(slice);
-            from_raw_parts(lens.as_ptr(), num_entries as usize).to_vec()
+        // Datasets without an expiry file keep their records forever
+        let expiry = if expiry_path.exists() {
+            let expiry_file = File::open(expiry_path)?;
+            let expiry_mmap = unsafe { memmap::Mmap::map(&expiry_file)? };
+            if expiry_mmap.len() as u64 != num_entries * 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "expiry file has {} bytes for {} entries",
+                        expiry_mmap.len(),
+                        num_entries
+                    ),
+                ));
+            }
+
+            Some(
+                expiry_mmap
+                    .chunks_exact(8)
+                    .map(LittleEndian::read_u64)
+                    .collect(),
+            )
+        } else {
+            None
         };
 
-        let _files = vec![uuid_mmap, offsets_mmap, lens_mmap];
+        // Written by compaction, so versions keep going up across it
+        let versions = if versions_path.exists() {
+            let versions_file = File::open(versions_path)?;
+            let versions_mmap = unsafe { memmap::Mmap::map(&versions_file)? };
+            if versions_mmap.len() as u64 != num_entries * 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "versions file has {} bytes for {} entries",
+                        versions_mmap.len(),
+                        num_entries
+                    ),
+                ));
+            }
+
+            Some(
+                versions_mmap
+                    .chunks_exact(8)
+                    .map(LittleEndian::read_u64)
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        let dictionary = if dictionary_path.exists() {
+            Some(fs::read(dictionary_path)?)
+        } else {
+            None
+        };
+
+        if dictionary.is_none()
+            && flags
+                .as_ref()
+                .map_or(false, |f| f.iter().any(|f| f & FLAG_COMPRESSED != 0))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dataset has compressed values but no dictionary",
+            ));
+        }
 
+        let max_len = lens.iter().cloned().max().unwrap_or(0);
         Ok(TableOfContents {
             uuids,
             offsets,
             lens,
-            _files,
+            checksums,
+            flags,
+            expiry,
+            versions,
+            dictionary,
+            max_len,
         })
     }
 
-    pub fn offset_and_len(&self, uuid: &[u8; 16]) -> Option<(u64, u16)> {
+    pub fn lookup(&self, uuid: &[u8; 16]) -> Option<Record> {
         match self.uuids.binary_search(uuid) {
-            Ok(index) => Some((self.offsets[index], self.lens[index])),
+            Ok(index) => Some(self.record(index)),
             Err(_) => None,
         }
     }
 
+    /// Record at position `index` in uuid order.
+    pub fn record(&self, index: usize) -> Record {
+        Record {
+            offset: self.offsets[index],
+            len: self.lens[index],
+            checksum: self.checksums.as_ref().map(|c| c[index]),
+            flags: self.flags.as_ref().map_or(0, |f| f[index]),
+            expires_at: self.expiry.as_ref().map_or(0, |e| e[index]),
+            version: self.versions.as_ref().map_or(1, |v| v[index]),
+        }
+    }
+
+    pub fn uuids(&self) -> &[[u8; 16]] {
+        &self.uuids
+    }
+
+    /// Indexes of the uuids from `start`, inclusive, to `end`, exclusive.
+    /// Without an end the range runs to the last uuid.
+    pub fn range(&self, start: &[u8; 16], end: Option<&[u8; 16]>) -> Range<usize> {
+        let first = match self.uuids.binary_search(start) {
+            Ok(index) | Err(index) => index,
+        };
+        let last = match end.map(|end| self.uuids.binary_search(end)) {
+            Some(Ok(index)) | Some(Err(index)) => index,
+            None => self.uuids.len(),
+        };
+        first..cmp::max(first, last)
+    }
+
+    pub fn offset_and_len(&self, uuid: &[u8; 16]) -> Option<(u64, u32)> {
+        self.lookup(uuid).map(|r| (r.offset, r.len))
+    }
+
     pub fn max_len(&self) -> usize {
-        *self.lens.iter().max().unwrap() as usize
+        self.max_len as usize
+    }
+
+    pub fn len(&self) -> usize {
+        self.uuids.len()
+    }
+
+    // Offset right past the last byte of the data file that any record
+    // points to.
+    pub fn data_end(&self) -> u64 {
+        self.offsets
+            .iter()
+            .zip(self.lens.iter())
+            .map(|(offset, len)| offset + *len as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_ref().map(|d| d.as_slice())
+    }
+}
+
+fn lens_width(file_len: u64, num_entries: u64) -> Result<u64, io::Error> {
+    if num_entries == 0 {
+        return Ok(4);
+    }
+    match file_len / num_entries {
+        width @ 2 | width @ 4 if file_len % num_entries == 0 => Ok(width),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "lengths file has {} bytes for {} entries",
+                file_len, num_entries
+            ),
+        )),
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
+pub(crate) mod tests {
+    use std::fs::{File, OpenOptions};
     use std::io::Write;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use tempdir::TempDir;
 
-    use super::TableOfContents;
+    use super::{hash_key, Record, TableOfContents, FLAG_COMPRESSED};
 
     use bytes::{ByteOrder, LittleEndian};
 
+    pub(crate) fn write_toc(
+        path: &Path,
+        uuids: &[[u8; 16]],
+        offsets: &[u64],
+        lens: &[u32],
+        len_width: usize,
+    ) {
+        let mut uuids_path = PathBuf::from(path);
+        let mut offsets_path = PathBuf::from(path);
+        let mut lens_path = PathBuf::from(path);
+        uuids_path.push("protostore.toc.uuids");
+        offsets_path.push("protostore.toc.offsets");
+        lens_path.push("protostore.toc.lengths");
+
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+
+        let mut uuids_file = opts.open(uuids_path).unwrap();
+        let mut offsets_file = opts.open(offsets_path).unwrap();
+        let mut lens_file = opts.open(lens_path).unwrap();
+
+        for uuid in uuids {
+            uuids_file.write_all(uuid).unwrap();
+        }
+
+        for offset in offsets {
+            let mut encoded_offset = [0; 8];
+            LittleEndian::write_u64(&mut encoded_offset, *offset);
+            offsets_file.write_all(&encoded_offset).unwrap();
+        }
+
+        for len in lens {
+            let mut encoded_len = [0; 4];
+            LittleEndian::write_uint(&mut encoded_len, *len as u64, len_width);
+            lens_file.write_all(&encoded_len[..len_width]).unwrap();
+        }
+    }
+
     #[test]
     fn open() {
         let tmp = TempDir::new("toc").unwrap();
         let path = tmp.into_path();
 
-        let mut uuids_path = PathBuf::from(path.clone());
-        let mut offsets_path = PathBuf::from(path.clone());
-        let mut lens_path = PathBuf::from(path.clone());
-        uuids_path.push("protostore.toc.uuids");
-        offsets_path.push("protostore.toc.offsets");
-        lens_path.push("protostore.toc.lengths");
-
         let uuids: Vec<[u8; 16]> = vec![
             [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
             [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
@@ -105,32 +423,9 @@ mod tests {
         ];
 
         let offsets: Vec<u64> = vec![0, 4, 8];
-        let lens: Vec<u16> = vec![4, 4, 4];
+        let lens: Vec<u32> = vec![4, 4, 4];
 
-        let mut opts = OpenOptions::new();
-        opts.write(true).create(true).truncate(true);
-
-        {
-            let mut uuids_file = opts.open(uuids_path).unwrap();
-            let mut offsets_file = opts.open(offsets_path).unwrap();
-            let mut lens_file = opts.open(lens_path).unwrap();
-
-            for uuid in &uuids {
-                uuids_file.write_all(uuid).unwrap();
-            }
-
-            for offset in &offsets {
-                let mut encoded_offset = [0; 8];
-                LittleEndian::write_u64(&mut encoded_offset, *offset);
-                offsets_file.write_all(&encoded_offset).unwrap();
-            }
-
-            for len in &lens {
-                let mut encoded_len = [0; 2];
-                LittleEndian::write_u16(&mut encoded_len, *len);
-                lens_file.write_all(&encoded_len).unwrap();
-            }
-        }
+        write_toc(&path, &uuids, &offsets, &lens, 2);
 
         let toc = TableOfContents::from_path(path.as_path());
         assert!(toc.is_ok());
@@ -144,5 +439,179 @@ mod tests {
             None,
             toc.offset_and_len(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4])
         );
+        assert_eq!(3, toc.len());
+        assert_eq!(12, toc.data_end());
+        assert_eq!(&uuids[..], toc.uuids());
+        assert_eq!(8, toc.record(2).offset);
+
+        assert_eq!(1..3, toc.range(&uuids[1], None));
+        assert_eq!(0..2, toc.range(&[0; 16], Some(&uuids[2])));
+        assert_eq!(
+            2..2,
+            toc.range(
+                &uuids[2],
+                Some(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3])
+            )
+        );
+    }
+
+    #[test]
+    fn decode_little_endian() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]];
+
+        write_toc(&path, &uuids, &[0x0102_0304_0506_0708], &[0x0a0b_0c0d], 4);
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        assert_eq!(
+            Some((0x0102_0304_0506_0708, 0x0a0b_0c0d)),
+            toc.offset_and_len(&uuids[0])
+        );
+    }
+
+    #[test]
+    fn reject_short_offsets() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        ];
+
+        write_toc(&path, &uuids, &[0], &[4, 4], 4);
+
+        assert!(TableOfContents::from_path(path.as_path()).is_err());
+    }
+
+    #[test]
+    fn open_wide_lengths() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        ];
+
+        let offsets: Vec<u64> = vec![0, 4];
+        let lens: Vec<u32> = vec![4, 1 << 20];
+
+        write_toc(&path, &uuids, &offsets, &lens, 4);
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        assert_eq!(Some((4, 1 << 20)), toc.offset_and_len(&uuids[1]));
+        assert_eq!(1 << 20, toc.max_len());
+    }
+
+    #[test]
+    fn open_with_checksums() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        ];
+
+        write_toc(&path, &uuids, &[0, 4], &[4, 4], 4);
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        assert_eq!(None, toc.lookup(&uuids[0]).unwrap().checksum);
+
+        let mut checksums_path = PathBuf::from(path.clone());
+        checksums_path.push("protostore.toc.checksums");
+        let mut checksums_file = File::create(checksums_path).unwrap();
+        for checksum in &[0xdeadbeef, 0xcafebabe] {
+            let mut encoded = [0; 4];
+            LittleEndian::write_u32(&mut encoded, *checksum);
+            checksums_file.write_all(&encoded).unwrap();
+        }
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        assert_eq!(
+            Some(Record {
+                offset: 4,
+                len: 4,
+                checksum: Some(0xcafebabe),
+                flags: 0,
+                expires_at: 0,
+                version: 1,
+            }),
+            toc.lookup(&uuids[1])
+        );
+    }
+
+    #[test]
+    fn open_compressed() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        ];
+
+        write_toc(&path, &uuids, &[0, 4], &[4, 4], 4);
+
+        let mut flags_path = PathBuf::from(path.clone());
+        flags_path.push("protostore.toc.flags");
+        File::create(flags_path)
+            .unwrap()
+            .write_all(&[0, FLAG_COMPRESSED])
+            .unwrap();
+
+        // Compressed values can't be served without the dictionary
+        assert!(TableOfContents::from_path(path.as_path()).is_err());
+
+        let mut dictionary_path = PathBuf::from(path.clone());
+        dictionary_path.push("protostore.dict");
+        File::create(dictionary_path)
+            .unwrap()
+            .write_all(b"dict")
+            .unwrap();
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        assert!(!toc.lookup(&uuids[0]).unwrap().is_compressed());
+        assert!(toc.lookup(&uuids[1]).unwrap().is_compressed());
+        assert_eq!(Some(&b"dict"[..]), toc.dictionary());
+    }
+
+    #[test]
+    fn hash_keys() {
+        assert_eq!(hash_key(b"example.com"), hash_key(b"example.com"));
+        assert_ne!(hash_key(b"example.com"), hash_key(b"example.org"));
+        assert_ne!(hash_key(b""), hash_key(b"\0"));
+    }
+
+    #[test]
+    fn open_with_expiry() {
+        let tmp = TempDir::new("toc").unwrap();
+        let path = tmp.into_path();
+
+        let uuids: Vec<[u8; 16]> = vec![
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        ];
+
+        write_toc(&path, &uuids, &[0, 4], &[4, 4], 4);
+
+        let mut expiry_path = PathBuf::from(path.clone());
+        expiry_path.push("protostore.toc.expiry");
+        let mut expiry_file = File::create(expiry_path).unwrap();
+        for expires_at in &[0, 1000] {
+            let mut encoded = [0; 8];
+            LittleEndian::write_u64(&mut encoded, *expires_at);
+            expiry_file.write_all(&encoded).unwrap();
+        }
+
+        let toc = TableOfContents::from_path(path.as_path()).unwrap();
+        let forever = toc.lookup(&uuids[0]).unwrap();
+        let expiring = toc.lookup(&uuids[1]).unwrap();
+        assert!(!forever.is_expired(u64::max_value()));
+        assert!(!expiring.is_expired(999));
+        assert!(expiring.is_expired(1000));
     }
 }